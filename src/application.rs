@@ -32,6 +32,8 @@ mod imp {
             obj.set_accels_for_action("win.scroll_to_end", &["<Shift>g"]);
             obj.set_accels_for_action("win.half_page_up", &["u"]);
             obj.set_accels_for_action("win.half_page_down", &["d"]);
+            obj.set_accels_for_action("win.repeat", &["r"]);
+            obj.set_accels_for_action("win.shuffle", &["s"]);
         }
     }
 
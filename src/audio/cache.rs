@@ -0,0 +1,478 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bilibili::Result,
+    config::CACHE_DIR,
+    library::{CacheEntry, Library},
+};
+
+use super::{tags, SongData};
+
+/// Bytes in a MiB, the unit of the cache limit setting
+pub const MIB: u64 = 1024 * 1024;
+
+/// Sidecar of the cache directory, naming the song behind each file
+const INDEX_FILE: &str = "index.json";
+
+/// End of the cached covers, named after the bvid of their video
+pub const COVER_SUFFIX: &str = "-cover.jpg";
+
+lazy_static! {
+    /// Downloads finish on their own threads, one at a time gets to
+    /// rewrite the index
+    static ref INDEX_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// What a cache key stands for, readable without the library
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub bvid: String,
+    pub cid: u32,
+    pub quality: u32,
+    pub codec: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub page: Option<u32>,
+}
+
+impl From<&SongData> for IndexEntry {
+    fn from(song: &SongData) -> Self {
+        let (quality, codec) = song
+            .stream()
+            .map_or((0, String::new()), |s| (s.quality, s.codec.clone()));
+        IndexEntry {
+            bvid: song.bvid(),
+            cid: song.cid(),
+            quality,
+            codec,
+            title: song.title().to_string(),
+            artist: song.artist().map(str::to_string),
+            album: song.album().map(str::to_string),
+            page: song.page(),
+        }
+    }
+}
+
+fn read_index(path: &Path) -> BTreeMap<String, IndexEntry> {
+    File::open(path)
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default()
+}
+
+/// Change the index at `path`, written to a temporary file first so that
+/// a crash never leaves half of it
+fn update_index<F: FnOnce(&mut BTreeMap<String, IndexEntry>)>(path: &Path, f: F) {
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut index = read_index(path);
+    f(&mut index);
+    let temp = path.with_extension("json.tmp");
+    let result = File::create(&temp).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &index)?;
+        writer.flush()?;
+        fs::rename(&temp, path)
+    });
+    if let Err(e) = result {
+        warn!("Unable to write {}: {}", path.display(), e);
+    }
+}
+
+fn index_path() -> PathBuf {
+    CACHE_DIR.join(INDEX_FILE)
+}
+
+/// Files in `dir` holding `song` at another quality than its current one,
+/// unfinished downloads included
+fn stale_files(dir: &Path, song: &SongData) -> Vec<PathBuf> {
+    let bvid = song.bvid();
+    let current = song.file_name();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.strip_suffix(".part").unwrap_or(name),
+                None => return false,
+            };
+            name != current && key_quality(Path::new(name), &bvid, song.cid()).is_some()
+        })
+        .collect()
+}
+
+/// Add the cached file of `song` to the index. The files it was cached in
+/// before at other qualities are deleted, nothing would account for them.
+pub fn index(song: &SongData) {
+    for path in stale_files(&CACHE_DIR, song) {
+        if fs::remove_file(&path).is_ok() {
+            debug!("Replaced by {}: {}", song.file_name(), path.display());
+        }
+    }
+    update_index(&index_path(), |index| {
+        index.retain(|_, entry| entry.bvid != song.bvid() || entry.cid != song.cid());
+        index.insert(song.cache_key(), IndexEntry::from(song));
+    });
+}
+
+fn unindex(song: &SongData) {
+    update_index(&index_path(), |index| {
+        index.remove(&song.cache_key());
+    });
+}
+
+/// The quality in the name of a file cached for the song `bvid`, `cid`
+pub fn key_quality(path: &Path, bvid: &str, cid: u32) -> Option<u32> {
+    let stem = path.file_stem()?.to_str()?;
+    let mut parts = stem.rsplitn(3, '-');
+    let quality = parts.next()?.parse().ok()?;
+    let key_cid: u32 = parts.next()?.parse().ok()?;
+    let key_bvid = parts.next()?;
+    (key_bvid == bvid && key_cid == cid).then(|| quality)
+}
+
+/// The name songs were cached under before their key, which songs of the
+/// same title shared
+fn legacy_file_name(song: &SongData) -> String {
+    let escape = |s: &str| s.replace('/', ",");
+    let stem = match song.album() {
+        Some(album) => format!("{}-{}", escape(album), escape(song.title())),
+        None => escape(song.title()),
+    };
+    format!("{}.{}", stem, song.extension())
+}
+
+/// Rename the files cached under their title to their key. A file goes to
+/// the song its tags name, or without tags to the one song it can be;
+/// the others are left alone.
+pub fn migrate(library: &Library) -> Result<()> {
+    let songs = library.songs()?;
+    let mut candidates: HashMap<String, Vec<&SongData>> = HashMap::new();
+    for song in &songs {
+        candidates
+            .entry(legacy_file_name(song))
+            .or_default()
+            .push(song);
+    }
+
+    for (name, songs) in candidates {
+        let legacy = CACHE_DIR.join(&name);
+        if !legacy.is_file() {
+            continue;
+        }
+        let song = match tags::read_tags(&legacy) {
+            Some(tagged) => songs.into_iter().find(|song| **song == tagged),
+            None if songs.len() == 1 => Some(songs[0]),
+            None => None,
+        };
+        let song = match song {
+            Some(song) => song,
+            None => {
+                warn!("Unable to tell which song {} is", legacy.display());
+                continue;
+            }
+        };
+        let target = song.cache_path();
+        if target.exists() {
+            debug!("{} is cached already, keeping {}", song.title(), name);
+            continue;
+        }
+        fs::rename(&legacy, &target)?;
+        let _ = fs::remove_file(CACHE_DIR.join(format!("{}.part", name)));
+        library.move_cache(song)?;
+        index(song);
+        debug!("Moved {} to {}", legacy.display(), target.display());
+    }
+    Ok(())
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
+
+/// The least recently used entries to drop to get the cache down to
+/// `limit` bytes. Pinned entries and the songs in `keep` stay.
+fn plan_eviction<'a>(
+    entries: &'a [CacheEntry],
+    limit: u64,
+    keep: &[SongData],
+) -> Vec<&'a CacheEntry> {
+    let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut candidates: Vec<&CacheEntry> = entries
+        .iter()
+        .filter(|entry| !entry.pinned && !keep.contains(&entry.song))
+        .collect();
+    candidates.sort_by_key(|entry| entry.last_access);
+
+    let mut evicted = Vec::new();
+    for entry in candidates {
+        if size <= limit {
+            break;
+        }
+        size = size.saturating_sub(entry.size);
+        evicted.push(entry);
+    }
+    evicted
+}
+
+/// Delete the cached file of `song`, with what is left of an unfinished
+/// download
+pub fn remove_file(song: &SongData) {
+    let path = song.cache_path();
+    if fs::remove_file(&path).is_ok() {
+        debug!("Clear cache: {}", path.display());
+    }
+    let _ = fs::remove_file(CACHE_DIR.join(format!("{}.part", song.file_name())));
+    unindex(song);
+    remove_unused_cover(&CACHE_DIR, song);
+}
+
+/// Delete the cover of the video of `song` from `dir` once none of its
+/// pages is cached there, unfinished downloads included
+fn remove_unused_cover(dir: &Path, song: &SongData) {
+    let cover = song.cover_file_name();
+    let prefix = format!("{}-", song.bvid());
+    let in_use = fs::read_dir(dir).map_or(true, |entries| {
+        entries.filter_map(|entry| entry.ok()).any(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name != cover.as_str() && name.starts_with(&prefix)
+        })
+    });
+    if !in_use && fs::remove_file(dir.join(&cover)).is_ok() {
+        debug!("Clear cache: {}", cover);
+    }
+}
+
+/// The covers in `dir` and the bvids of their videos
+fn covers(dir: &Path) -> Vec<(PathBuf, String)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let bvid = name.strip_suffix(COVER_SUFFIX)?.to_string();
+            Some((path, bvid))
+        })
+        .collect()
+}
+
+/// Bytes taken by the cached covers, which are not cache entries
+pub fn covers_size() -> u64 {
+    covers(&CACHE_DIR)
+        .iter()
+        .filter_map(|(path, _)| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Record that the cached file of `song` was just downloaded or played
+pub fn touch(library: &Library, song: &SongData) -> Result<()> {
+    let metadata = fs::metadata(song.cache_path())?;
+    library.touch_cache(song, metadata.len(), unix_time(SystemTime::now()))
+}
+
+/// Track the cached files of the songs in the library which were there
+/// before the cache was, and forget about the ones deleted behind our back
+pub fn sync(library: &Library) -> Result<()> {
+    let tracked = library.cache_entries()?;
+    for entry in &tracked {
+        if !entry.song.cache_path().exists() {
+            library.forget_cache(&entry.song)?;
+        }
+    }
+    for song in library.songs()? {
+        if tracked.iter().any(|entry| entry.song == song) {
+            continue;
+        }
+        if let Ok(metadata) = fs::metadata(song.cache_path()) {
+            let accessed = metadata
+                .accessed()
+                .or_else(|_| metadata.modified())
+                .map_or(0, unix_time);
+            library.touch_cache(&song, metadata.len(), accessed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete the least recently used files until the cache fits in `limit`
+/// bytes, returning the songs which are no longer cached
+pub fn evict(library: &Library, limit: u64, keep: &[SongData]) -> Result<Vec<SongData>> {
+    let entries = library.cache_entries()?;
+    let mut evicted = Vec::new();
+    for entry in plan_eviction(&entries, limit, keep) {
+        debug!("Evict {} from the cache", entry.song.title());
+        remove_file(&entry.song);
+        library.forget_cache(&entry.song)?;
+        evicted.push(entry.song.clone());
+    }
+    Ok(evicted)
+}
+
+/// Delete the covers in `dir` except those of the videos of `keep`
+fn clear_covers(dir: &Path, keep: &[SongData]) {
+    for (path, bvid) in covers(dir) {
+        if !keep.iter().any(|song| song.bvid() == bvid) && fs::remove_file(&path).is_ok() {
+            debug!("Clear cache: {}", path.display());
+        }
+    }
+}
+
+/// Delete every cached file except those of the songs in `keep`, covers
+/// included
+pub fn clear(library: &Library, keep: &[SongData]) -> Result<Vec<SongData>> {
+    let mut removed = Vec::new();
+    for entry in library.cache_entries()? {
+        if keep.contains(&entry.song) {
+            continue;
+        }
+        remove_file(&entry.song);
+        if let Err(e) = library.forget_cache(&entry.song) {
+            warn!("Failed to forget {}: {}", entry.song.title(), e);
+        }
+        removed.push(entry.song);
+    }
+    clear_covers(&CACHE_DIR, keep);
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(bvid: &str, size: u64, last_access: i64, pinned: bool) -> CacheEntry {
+        let json = format!(
+            r#"{{"artist":"up","title":"{}","duration":64,"bvid":"{}","cid":1,"album":null}}"#,
+            bvid, bvid
+        );
+        CacheEntry {
+            song: serde_json::from_str(&json).unwrap(),
+            size,
+            last_access,
+            pinned,
+        }
+    }
+
+    #[test]
+    fn test_index() {
+        let path = std::env::temp_dir().join(format!("index-{}.json", std::process::id()));
+        let song = entry("BV1", 0, 0, false).song;
+        update_index(&path, |index| {
+            index.insert(song.cache_key(), IndexEntry::from(&song));
+        });
+        let index = read_index(&path);
+        assert_eq!(index["BV1-1-0"].title, "BV1");
+        assert_eq!(legacy_file_name(&song), "BV1.m4a");
+
+        update_index(&path, |index| {
+            index.remove(&song.cache_key());
+        });
+        assert!(read_index(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_stale_files() {
+        let dir = std::env::temp_dir().join(format!("cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "BV1-1-100.m4a",
+            "BV1-1-100.m4a.part",
+            "BV1-1-200.m4a",
+            "BV1-2-100.m4a",
+            "BV2-1-100.m4a",
+            INDEX_FILE,
+        ] {
+            File::create(dir.join(name)).unwrap();
+        }
+
+        let json = r#"{"artist":"up","title":"BV1","duration":64,"bvid":"BV1","cid":1,"album":null,
+            "stream":{"quality":200,"codec":"mp4a.40.2","extension":"m4a"}}"#;
+        let song: SongData = serde_json::from_str(json).unwrap();
+        let mut stale = stale_files(&dir, &song);
+        stale.sort();
+        assert_eq!(
+            stale,
+            [dir.join("BV1-1-100.m4a"), dir.join("BV1-1-100.m4a.part")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_covers() {
+        let dir = std::env::temp_dir().join(format!("covers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "BV1-cover.jpg",
+            "BV1-2-100.m4a",
+            "BV2-cover.jpg",
+            "BV3-cover.jpg",
+        ] {
+            File::create(dir.join(name)).unwrap();
+        }
+        let song = |bvid: &str| entry(bvid, 0, 0, false).song;
+
+        // Another page of BV1 is still cached
+        remove_unused_cover(&dir, &song("BV1"));
+        assert!(dir.join("BV1-cover.jpg").exists());
+        remove_unused_cover(&dir, &song("BV2"));
+        assert!(!dir.join("BV2-cover.jpg").exists());
+
+        clear_covers(&dir, &[song("BV3")]);
+        let mut bvids: Vec<String> = covers(&dir).into_iter().map(|(_, bvid)| bvid).collect();
+        bvids.sort();
+        assert_eq!(bvids, ["BV3"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_quality() {
+        let path = Path::new("/cache/BV1xx411c7mD-1176840-30280.m4a");
+        assert_eq!(key_quality(path, "BV1xx411c7mD", 1176840), Some(30280));
+        assert_eq!(key_quality(path, "BV1xx411c7mD", 1), None);
+        assert_eq!(key_quality(Path::new("/cache/歌-1.m4a"), "歌", 1), None);
+    }
+
+    #[test]
+    fn test_plan_eviction() {
+        let entries = vec![
+            entry("BV1", 300, 100, false),
+            entry("BV2", 300, 50, true),
+            entry("BV3", 300, 200, false),
+            entry("BV4", 300, 10, false),
+        ];
+        let bvids = |limit, keep: &[SongData]| -> Vec<String> {
+            plan_eviction(&entries, limit, keep)
+                .iter()
+                .map(|entry| entry.song.bvid())
+                .collect()
+        };
+
+        assert_eq!(bvids(1200, &[]), Vec::<String>::new());
+        // Least recently used first, the pinned one stays
+        assert_eq!(bvids(900, &[]), ["BV4"]);
+        assert_eq!(bvids(500, &[]), ["BV4", "BV1"]);
+        // The current song is kept even if the limit can not be met
+        let current = entries[3].song.clone();
+        assert_eq!(bvids(0, &[current]), ["BV1", "BV3"]);
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use gstreamer_player::{
+    gst::{self, prelude::*},
+    prelude::Cast,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{tags, SongData};
+
+/// File name of exported songs relative to the export directory
+pub const DEFAULT_TEMPLATE: &str = "{artist}/{album}/{page:02} - {title}.{ext}";
+
+/// What the cached files are turned into on export
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// The cached file as it is
+    Copy,
+    Opus,
+    Mp3,
+    Flac,
+}
+
+impl Default for ExportFormat {
+    fn default() -> Self {
+        ExportFormat::Copy
+    }
+}
+
+impl ExportFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportFormat::Copy => "copy",
+            ExportFormat::Opus => "opus",
+            ExportFormat::Mp3 => "mp3",
+            ExportFormat::Flac => "flac",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "copy" => Some(ExportFormat::Copy),
+            "opus" => Some(ExportFormat::Opus),
+            "mp3" => Some(ExportFormat::Mp3),
+            "flac" => Some(ExportFormat::Flac),
+            _ => None,
+        }
+    }
+
+    /// The extension of the exported file, copies keep the cached one
+    fn extension<'a>(&self, data: &'a SongData) -> &'a str {
+        match self {
+            ExportFormat::Copy => data.extension(),
+            ExportFormat::Opus => "opus",
+            ExportFormat::Mp3 => "mp3",
+            ExportFormat::Flac => "flac",
+        }
+    }
+
+    /// The end of the transcoding pipeline, None for copies
+    fn encoder(&self) -> Option<&'static str> {
+        match self {
+            ExportFormat::Copy => None,
+            ExportFormat::Opus => Some("opusenc bitrate=160000 ! oggmux"),
+            ExportFormat::Mp3 => Some("lamemp3enc target=quality quality=2"),
+            ExportFormat::Flac => Some("flacenc"),
+        }
+    }
+}
+
+/// Replace the separators in a value, only the template makes directories.
+/// A value of `.` or `..` gets full-width dots so it stays a name.
+fn escape(value: &str) -> String {
+    let value = value.replace('/', ",").replace('\0', "");
+    match value.trim() {
+        "." | ".." => value.replace('.', "．"),
+        _ => value,
+    }
+}
+
+/// Fill in `{artist}`, `{album}`, `{title}`, `{page}`, `{bvid}`, `{cid}` and
+/// `{ext}`; numbers take a width like `{page:02}`. Songs outside of a
+/// multi-part video are their own album and page 1 of it.
+pub fn render_template(template: &str, data: &SongData, ext: &str) -> PathBuf {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        rendered.push_str(&rest[..start]);
+        let field = &rest[start + 1..end];
+        let (name, width) = match field.split_once(':') {
+            Some((name, width)) => (name, width.parse::<usize>().ok()),
+            None => (field, None),
+        };
+        let number = |n: u32| match width {
+            Some(width) => format!("{:0width$}", n, width = width),
+            None => n.to_string(),
+        };
+        let value = match name {
+            "artist" => data.artist().unwrap_or("未知艺术家").to_string(),
+            "album" => data.album().unwrap_or_else(|| data.title()).to_string(),
+            "title" => data.title().to_string(),
+            "page" => number(data.page().unwrap_or(1)),
+            "bvid" => data.bvid(),
+            "cid" => number(data.cid()),
+            "ext" => ext.to_string(),
+            // Not a field, keep it as it is
+            _ => rest[start..=end].to_string(),
+        };
+        rendered.push_str(&escape(&value));
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+
+    rendered
+        .split('/')
+        .map(str::trim)
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .collect()
+}
+
+/// `path` unless another song is there already or goes there in this
+/// export, then the bvid and cid are added to the name. A file of the same
+/// song is replaced.
+fn unique_path(path: PathBuf, data: &SongData, taken: &HashSet<PathBuf>) -> PathBuf {
+    let free = |path: &Path| {
+        !taken.contains(path)
+            && (!path.exists() || tags::read_tags(path).map_or(false, |other| other == *data))
+    };
+    if free(&path) {
+        return path;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!(
+            "{} [{}-{}].{}",
+            stem,
+            data.bvid(),
+            data.cid(),
+            ext.to_string_lossy()
+        ),
+        None => format!("{} [{}-{}]", stem, data.bvid(), data.cid()),
+    };
+    path.with_file_name(name)
+}
+
+/// Decode `source` and encode it with `encoder` into `target`
+fn transcode(source: &Path, target: &Path, encoder: &str) -> Result<()> {
+    let pipeline = gst::parse_launch(&format!(
+        "filesrc name=src ! decodebin ! audioconvert ! audioresample ! {} ! filesink name=sink",
+        encoder
+    ))?;
+    let bin = pipeline
+        .downcast_ref::<gst::Bin>()
+        .ok_or_else(|| anyhow!("The export pipeline is no bin"))?;
+    let src = bin
+        .by_name("src")
+        .ok_or_else(|| anyhow!("No filesrc in the export pipeline"))?;
+    src.set_property("location", source.to_string_lossy().to_string());
+    let sink = bin
+        .by_name("sink")
+        .ok_or_else(|| anyhow!("No filesink in the export pipeline"))?;
+    sink.set_property("location", target.to_string_lossy().to_string());
+
+    let bus = pipeline.bus().ok_or_else(|| anyhow!("No bus"))?;
+    pipeline.set_state(gst::State::Playing)?;
+
+    let mut result = Ok(());
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        match msg.view() {
+            gst::MessageView::Eos(..) => break,
+            gst::MessageView::Error(err) => {
+                result = Err(anyhow!("{}", err.error()));
+                break;
+            }
+            _ => {}
+        }
+    }
+    pipeline.set_state(gst::State::Null)?;
+    result
+}
+
+/// Where the songs go and in which format
+#[derive(Clone)]
+pub struct ExportOptions {
+    pub dir: PathBuf,
+    pub template: String,
+    pub format: ExportFormat,
+}
+
+pub enum ExportProgress {
+    /// The song at the index is being exported
+    Started(usize, String),
+    /// Everything is done or the export was cancelled, with a message for
+    /// each song that failed
+    Finished(Vec<String>),
+}
+
+fn export_song(data: &SongData, target: &Path, format: ExportFormat) -> Result<()> {
+    let source = data.cache_path();
+    if !source.exists() {
+        return Err(anyhow!("还没有缓存"));
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match format.encoder() {
+        Some(encoder) => {
+            if let Err(e) = transcode(&source, target, encoder) {
+                let _ = fs::remove_file(target);
+                return Err(e);
+            }
+        }
+        None => {
+            fs::copy(&source, target)?;
+        }
+    }
+
+    let cover = data.cover_path();
+    let cover = cover.exists().then(|| cover.as_path());
+    if let Err(e) = tags::write_tags(target, data, cover) {
+        warn!("Unable to tag {}: {}", target.display(), e);
+    }
+    Ok(())
+}
+
+/// Export the songs one after another, this takes a while and should not
+/// run on the main thread. `progress` hears about each song and the end;
+/// setting `cancel` stops before the next song.
+pub fn export_songs<F: Fn(ExportProgress)>(
+    songs: &[SongData],
+    options: &ExportOptions,
+    cancel: Arc<AtomicBool>,
+    progress: F,
+) {
+    let mut taken = HashSet::new();
+    let mut failed = Vec::new();
+    for (index, data) in songs.iter().enumerate() {
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        progress(ExportProgress::Started(index, data.title().to_string()));
+        let ext = options.format.extension(data);
+        let relative = render_template(&options.template, data, ext);
+        let target = unique_path(options.dir.join(relative), data, &taken);
+        match export_song(data, &target, options.format) {
+            Ok(()) => {
+                taken.insert(target);
+            }
+            Err(e) => {
+                warn!("Failed to export {}: {}", data.title(), e);
+                failed.push(format!("{}: {}", data.title(), e));
+            }
+        }
+    }
+    progress(ExportProgress::Finished(failed));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn song(album: Option<&str>, page: Option<u32>) -> SongData {
+        let json = serde_json::json!({
+            "artist": "up/主",
+            "title": "歌",
+            "duration": 64,
+            "bvid": "BV1xx411c7mD",
+            "cid": 1176840,
+            "album": album,
+            "page": page,
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_render_template() {
+        let data = song(Some("合集"), Some(3));
+        assert_eq!(
+            render_template(DEFAULT_TEMPLATE, &data, "m4a"),
+            Path::new("up,主/合集/03 - 歌.m4a")
+        );
+        assert_eq!(
+            render_template("{title} [{bvid}-{cid}] {unknown}.{ext}", &data, "opus"),
+            Path::new("歌 [BV1xx411c7mD-1176840] {unknown}.opus")
+        );
+        // An unclosed brace is text
+        assert_eq!(
+            render_template("{title}.{ext} - {page", &data, "mp3"),
+            Path::new("歌.mp3 - {page")
+        );
+
+        // A single video is its own album
+        let data = song(None, None);
+        assert_eq!(
+            render_template("../{album}//{page} - {title}.{ext}", &data, "flac"),
+            Path::new("歌/1 - 歌.flac")
+        );
+
+        // Values are names even when they look like directories
+        let data: SongData = serde_json::from_value(serde_json::json!({
+            "title": ".",
+            "duration": 64,
+            "bvid": "BV1xx411c7mD",
+            "cid": 1176840,
+            "album": "..",
+        }))
+        .unwrap();
+        assert_eq!(
+            render_template("{album}/{title}/{title}.{ext}", &data, "m4a"),
+            Path::new("．．/．/．.m4a")
+        );
+    }
+
+    #[test]
+    fn test_unique_path() {
+        let data = song(None, None);
+        let path = PathBuf::from("/nonexistent/歌.m4a");
+        let mut taken = HashSet::new();
+        assert_eq!(unique_path(path.clone(), &data, &taken), path);
+        taken.insert(path.clone());
+        assert_eq!(
+            unique_path(path, &data, &taken),
+            Path::new("/nonexistent/歌 [BV1xx411c7mD-1176840].m4a")
+        );
+    }
+}
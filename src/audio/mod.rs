@@ -1,9 +1,20 @@
+mod cache;
+mod export;
+mod mpris;
 mod player;
 mod queue;
+mod replaygain;
 mod shuffle;
 mod song;
 mod state;
+mod tags;
 
-pub use player::{AudioPlayer, PlayerAction, RepeatMode};
+pub use export::{
+    export_songs, ExportFormat, ExportOptions, ExportProgress,
+    DEFAULT_TEMPLATE as DEFAULT_EXPORT_TEMPLATE,
+};
+pub use player::{AudioPlayer, PlayerAction, PlayerError, RepeatMode};
 pub use queue::Queue;
+pub use replaygain::{ReplayGainMode, MAX_PRE_AMP};
 pub use song::{Song, SongData};
+pub use tags::scan_cache;
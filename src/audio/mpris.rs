@@ -0,0 +1,631 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use gtk::glib::{self, clone, Sender};
+use log::warn;
+use zbus::{
+    blocking::{Connection, ConnectionBuilder},
+    dbus_interface,
+    zvariant::{ObjectPath, OwnedValue, Value},
+    SignalContext,
+};
+
+use crate::config::APPLICATION_ID;
+
+use super::{
+    player::PlaybackState, queue::Queue, state::PlayerState, PlayerAction, RepeatMode, Song,
+};
+
+const MPRIS_BUS_NAME: &str = "org.mpris.MediaPlayer2.bilibili_music";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+const USEC_PER_SEC: i64 = 1_000_000;
+
+/// org.mpris.MediaPlayer2
+struct MprisRoot;
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl MprisRoot {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> &str {
+        "BiliBili"
+    }
+
+    #[dbus_interface(property)]
+    fn desktop_entry(&self) -> &str {
+        APPLICATION_ID
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// org.mpris.MediaPlayer2.Player
+///
+/// Method calls arrive on the zbus executor thread, so they are forwarded
+/// to the AudioPlayer as PlayerAction; the properties are a snapshot of
+/// PlayerState pushed from the main thread by MprisController.
+struct MprisPlayer {
+    tx: Arc<Sender<PlayerAction>>,
+    status: PlaybackState,
+    track_id: Option<String>,
+    title: String,
+    artist: String,
+    album: String,
+    /// file uri of the cached cover
+    art_url: Option<String>,
+    /// seconds
+    duration: u64,
+    /// seconds
+    position: u64,
+    volume: f64,
+    repeat_mode: RepeatMode,
+    shuffled: bool,
+}
+
+impl MprisPlayer {
+    fn new(tx: Arc<Sender<PlayerAction>>) -> Self {
+        Self {
+            tx,
+            status: PlaybackState::Stopped,
+            track_id: None,
+            title: String::new(),
+            artist: String::new(),
+            album: String::new(),
+            art_url: None,
+            duration: 0,
+            position: 0,
+            volume: 1.0,
+            repeat_mode: RepeatMode::default(),
+            shuffled: false,
+        }
+    }
+
+    fn send(&self, action: PlayerAction) {
+        if self.tx.send(action).is_err() {
+            warn!("mpris: player is gone");
+        }
+    }
+
+    fn track_path(&self) -> ObjectPath<'static> {
+        self.track_id
+            .as_ref()
+            .and_then(|id| ObjectPath::try_from(id.clone()).ok())
+            .unwrap_or_else(|| ObjectPath::from_static_str_unchecked(NO_TRACK))
+    }
+}
+
+/// Object paths only allow `[A-Za-z0-9_]` in their elements
+fn track_id(bvid: &str, cid: u32) -> String {
+    let bvid: String = bvid
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("/org/bilibili/music/track/{}_{}", bvid, cid)
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MprisPlayer {
+    fn next(&self) {
+        self.send(PlayerAction::SkipNext);
+    }
+
+    fn previous(&self) {
+        self.send(PlayerAction::SkipPrevious);
+    }
+
+    fn pause(&self) {
+        if self.status == PlaybackState::Playing {
+            self.send(PlayerAction::TogglePlay);
+        }
+    }
+
+    fn play_pause(&self) {
+        self.send(PlayerAction::TogglePlay);
+    }
+
+    fn stop(&self) {
+        self.send(PlayerAction::Stop);
+    }
+
+    fn play(&self) {
+        if self.status != PlaybackState::Playing {
+            self.send(PlayerAction::TogglePlay);
+        }
+    }
+
+    /// `offset` is relative to the current position, in microseconds
+    fn seek(&self, offset: i64) {
+        if self.duration == 0 {
+            return;
+        }
+        let position = self.position as i64 * USEC_PER_SEC + offset;
+        if position < 0 {
+            self.send(PlayerAction::Seek(0.0));
+        } else if position > self.duration as i64 * USEC_PER_SEC {
+            self.send(PlayerAction::SkipNext);
+        } else {
+            let percent = position as f64 / (self.duration as i64 * USEC_PER_SEC) as f64;
+            self.send(PlayerAction::Seek(percent));
+        }
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        if track_id != self.track_path() || self.duration == 0 {
+            return;
+        }
+        let duration = self.duration as i64 * USEC_PER_SEC;
+        if (0..=duration).contains(&position) {
+            self.send(PlayerAction::Seek(position as f64 / duration as f64));
+        }
+    }
+
+    fn open_uri(&self, _uri: &str) {}
+
+    #[dbus_interface(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> &str {
+        match self.status {
+            PlaybackState::Playing => "Playing",
+            PlaybackState::Paused => "Paused",
+            PlaybackState::Stopped => "Stopped",
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn loop_status(&self) -> &str {
+        match self.repeat_mode {
+            RepeatMode::Consecutive => "None",
+            RepeatMode::RepeatAll => "Playlist",
+            RepeatMode::RepeatOne => "Track",
+        }
+    }
+
+    /// Repeat is only changed from the window, which keeps it in the settings
+    #[dbus_interface(property)]
+    fn set_loop_status(&mut self, _loop_status: String) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::PropertyReadOnly(
+            "LoopStatus is read-only".to_string(),
+        ))
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn shuffle(&self) -> bool {
+        self.shuffled
+    }
+
+    /// Shuffle is only changed from the window, which keeps it in the settings
+    #[dbus_interface(property)]
+    fn set_shuffle(&mut self, _shuffle: bool) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::PropertyReadOnly(
+            "Shuffle is read-only".to_string(),
+        ))
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let mut map = HashMap::new();
+        map.insert(
+            "mpris:trackid".to_string(),
+            Value::from(self.track_path()).into(),
+        );
+        if self.track_id.is_some() {
+            map.insert(
+                "mpris:length".to_string(),
+                Value::from(self.duration as i64 * USEC_PER_SEC).into(),
+            );
+            map.insert(
+                "xesam:title".to_string(),
+                Value::from(self.title.clone()).into(),
+            );
+            map.insert(
+                "xesam:artist".to_string(),
+                Value::from(vec![self.artist.clone()]).into(),
+            );
+            map.insert(
+                "xesam:album".to_string(),
+                Value::from(self.album.clone()).into(),
+            );
+            if let Some(art_url) = &self.art_url {
+                map.insert(
+                    "mpris:artUrl".to_string(),
+                    Value::from(art_url.clone()).into(),
+                );
+            }
+        }
+        map
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        self.volume
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&mut self, volume: f64) {
+        self.send(PlayerAction::SetVolume(volume.clamp(0.0, 1.0)));
+    }
+
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        self.position as i64 * USEC_PER_SEC
+    }
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        self.track_id.is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        self.track_id.is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        self.track_id.is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// MprisController owns the D-Bus connection and mirrors PlayerState
+/// into the org.mpris.MediaPlayer2.Player properties
+#[derive(Clone)]
+pub struct MprisController {
+    connection: Connection,
+}
+
+impl MprisController {
+    pub fn new(tx: Arc<Sender<PlayerAction>>) -> Result<Self> {
+        Self::build(ConnectionBuilder::session()?, tx)
+    }
+
+    /// Connect to the bus at `address` instead of the session bus
+    pub fn with_address(address: &str, tx: Arc<Sender<PlayerAction>>) -> Result<Self> {
+        Self::build(ConnectionBuilder::address(address)?, tx)
+    }
+
+    fn build(builder: ConnectionBuilder, tx: Arc<Sender<PlayerAction>>) -> Result<Self> {
+        let connection = builder
+            .name(MPRIS_BUS_NAME)?
+            .serve_at(MPRIS_PATH, MprisRoot)?
+            .serve_at(MPRIS_PATH, MprisPlayer::new(tx))?
+            .build()?;
+        Ok(Self { connection })
+    }
+
+    pub fn bind_state(&self, state: &PlayerState) {
+        state.connect_notify_local(
+            Some("playing"),
+            clone!(@strong self as this => move |state, _| {
+                this.set_playback_state(state.playback_state());
+            }),
+        );
+        state.connect_notify_local(
+            Some("song"),
+            clone!(@strong self as this => move |state, _| {
+                this.set_song(state.current_song().as_ref());
+            }),
+        );
+        state.connect_notify_local(
+            Some("position"),
+            clone!(@strong self as this => move |state, _| {
+                this.set_position(state.position());
+            }),
+        );
+        state.connect_notify_local(
+            Some("cover"),
+            clone!(@strong self as this => move |state, _| {
+                this.set_cover(state.cover());
+            }),
+        );
+        state.connect_notify_local(
+            Some("volume"),
+            clone!(@strong self as this => move |state, _| {
+                this.set_volume(state.volume());
+            }),
+        );
+    }
+
+    /// Mirror the repeat mode and shuffle of the queue
+    pub fn bind_queue(&self, queue: &Queue) {
+        self.set_repeat_mode(queue.repeat_mode());
+        self.set_shuffled(queue.shuffled());
+        queue.connect_notify_local(
+            Some("repeat-mode"),
+            clone!(@strong self as this => move |queue, _| {
+                this.set_repeat_mode(queue.repeat_mode());
+            }),
+        );
+        queue.connect_notify_local(
+            Some("shuffled"),
+            clone!(@strong self as this => move |queue, _| {
+                this.set_shuffled(queue.shuffled());
+            }),
+        );
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut MprisPlayer, &SignalContext<'_>) -> zbus::Result<()>,
+    {
+        let res = self
+            .connection
+            .object_server()
+            .interface::<_, MprisPlayer>(MPRIS_PATH)
+            .and_then(|iface_ref| {
+                let mut iface = iface_ref.get_mut();
+                f(&mut iface, iface_ref.signal_context())
+            });
+        if let Err(e) = res {
+            warn!("mpris: failed to update properties: {}", e);
+        }
+    }
+
+    fn set_playback_state(&self, state: PlaybackState) {
+        self.update(|iface, ctxt| {
+            iface.status = state;
+            zbus::block_on(iface.playback_status_changed(ctxt))
+        });
+    }
+
+    fn set_song(&self, song: Option<&Song>) {
+        self.update(|iface, ctxt| {
+            if let Some(song) = song {
+                iface.track_id = Some(track_id(&song.bvid(), song.cid()));
+                iface.title = song.title();
+                iface.artist = song.artist();
+                iface.album = song.album();
+                iface.duration = song.duration();
+            } else {
+                iface.track_id = None;
+                iface.duration = 0;
+            }
+            iface.position = 0;
+            zbus::block_on(iface.metadata_changed(ctxt))?;
+            zbus::block_on(iface.can_play_changed(ctxt))?;
+            zbus::block_on(iface.can_pause_changed(ctxt))?;
+            zbus::block_on(iface.can_seek_changed(ctxt))
+        });
+    }
+
+    fn set_cover(&self, cover: Option<String>) {
+        self.update(|iface, ctxt| {
+            let art_url = cover.and_then(|path| glib::filename_to_uri(path, None).ok());
+            let art_url = art_url.map(|uri| uri.to_string());
+            if art_url == iface.art_url {
+                return Ok(());
+            }
+            iface.art_url = art_url;
+            zbus::block_on(iface.metadata_changed(ctxt))
+        });
+    }
+
+    fn set_position(&self, position: u64) {
+        self.update(|iface, ctxt| {
+            // Position is not announced through PropertiesChanged; clients
+            // only need to hear about jumps, which is what Seeked is for
+            let jumped = position < iface.position || position > iface.position + 2;
+            iface.position = position;
+            if jumped {
+                zbus::block_on(MprisPlayer::seeked(ctxt, position as i64 * USEC_PER_SEC))?;
+            }
+            Ok(())
+        });
+    }
+
+    fn set_volume(&self, volume: f64) {
+        self.update(|iface, ctxt| {
+            iface.volume = volume;
+            zbus::block_on(iface.volume_changed(ctxt))
+        });
+    }
+
+    fn set_repeat_mode(&self, repeat_mode: RepeatMode) {
+        self.update(|iface, ctxt| {
+            iface.repeat_mode = repeat_mode;
+            zbus::block_on(iface.loop_status_changed(ctxt))
+        });
+    }
+
+    fn set_shuffled(&self, shuffled: bool) {
+        self.update(|iface, ctxt| {
+            iface.shuffled = shuffled;
+            zbus::block_on(iface.shuffle_changed(ctxt))
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    use zbus::blocking::{Connection, ConnectionBuilder};
+
+    use super::*;
+
+    fn call(client: &Connection, method: &str) {
+        client
+            .call_method(
+                Some(MPRIS_BUS_NAME),
+                MPRIS_PATH,
+                Some("org.mpris.MediaPlayer2.Player"),
+                method,
+                &(),
+            )
+            .unwrap();
+    }
+
+    /// Kills the bus when the test ends, whether it passed or not
+    struct Daemon(Child);
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    #[test]
+    fn test_track_id() {
+        assert_eq!(
+            track_id("BV1xx411c7mD", 1176840),
+            "/org/bilibili/music/track/BV1xx411c7mD_1176840"
+        );
+        let id = track_id("Invalid bvid", 0);
+        assert_eq!(id, "/org/bilibili/music/track/Invalid_bvid_0");
+        assert!(ObjectPath::try_from(id).is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn test_mpris() {
+        // A private bus, so the test never touches the user's session
+        let mut daemon = Daemon(
+            Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("dbus-daemon not found"),
+        );
+        let mut address = String::new();
+        BufReader::new(daemon.0.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim();
+
+        let context = glib::MainContext::new();
+        let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        let received: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        rx.attach(
+            Some(&context),
+            clone!(@strong received => move |action| {
+                let name = match action {
+                    PlayerAction::TogglePlay => "toggle",
+                    PlayerAction::SkipNext => "next",
+                    PlayerAction::SkipPrevious => "previous",
+                    PlayerAction::Stop => "stop",
+                    _ => "other",
+                };
+                received.borrow_mut().push(name.to_string());
+                glib::Continue(true)
+            }),
+        );
+
+        let controller = MprisController::with_address(address, Arc::new(tx)).unwrap();
+        controller.set_playback_state(PlaybackState::Playing);
+
+        let client = ConnectionBuilder::address(address)
+            .unwrap()
+            .build()
+            .unwrap();
+        call(&client, "PlayPause");
+        call(&client, "Next");
+        call(&client, "Previous");
+        call(&client, "Stop");
+
+        let proxy = zbus::blocking::fdo::PropertiesProxy::builder(&client)
+            .destination(MPRIS_BUS_NAME)
+            .unwrap()
+            .path(MPRIS_PATH)
+            .unwrap()
+            .build()
+            .unwrap();
+        let status = proxy
+            .get(
+                "org.mpris.MediaPlayer2.Player".try_into().unwrap(),
+                "PlaybackStatus",
+            )
+            .unwrap();
+        assert_eq!(status, Value::from("Playing").into());
+
+        controller.set_repeat_mode(RepeatMode::RepeatOne);
+        let loop_status = proxy
+            .get(
+                "org.mpris.MediaPlayer2.Player".try_into().unwrap(),
+                "LoopStatus",
+            )
+            .unwrap();
+        assert_eq!(loop_status, Value::from("Track").into());
+        assert!(proxy
+            .set(
+                "org.mpris.MediaPlayer2.Player".try_into().unwrap(),
+                "Shuffle",
+                &Value::from(true),
+            )
+            .is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.borrow().len() < 4 && Instant::now() < deadline {
+            context.iteration(false);
+        }
+        assert_eq!(
+            *received.borrow(),
+            vec!["toggle", "next", "previous", "stop"]
+        );
+    }
+}
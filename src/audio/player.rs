@@ -1,15 +1,52 @@
-use std::{rc::Rc, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    f64::consts::FRAC_PI_2,
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use gstreamer_player::{gst::ClockTime, prelude::Cast};
-use gtk::glib::{self, clone, Sender};
+use gstreamer_player::{
+    gst::{self, ClockTime},
+    prelude::{Cast, ObjectExt},
+};
+use gtk::{
+    gio::{self, prelude::*},
+    glib::{self, clone, Sender},
+};
 
-use crate::bilibili::data::parse_config;
+use crate::{
+    bilibili::{self, data::PlaybackData, BiliInput, Error, Result, BILIBILI_REFERER, BILIBILI_UA},
+    library::{CacheEntry, Library},
+    settings,
+};
 
-use super::{queue::Queue, song::SongData, state::PlayerState, Song};
-use log::debug;
+use super::{
+    cache::{self, MIB},
+    mpris::MprisController,
+    queue::Queue,
+    replaygain::{self, ReplayGain, ReplayGainMode},
+    song::SongData,
+    state::PlayerState,
+    Song,
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, glib::Enum, PartialEq)]
+/// Seconds between saves of the playback position
+const SAVE_INTERVAL: u32 = 5;
+
+/// Seconds of a song after which it counts as listened to, unless half of
+/// it is reached before
+const LISTEN_THRESHOLD: u64 = 240;
+
+#[derive(Clone, Copy, Debug, glib::Enum, PartialEq, Deserialize, Serialize)]
 #[enum_type(name = "PlayerRepeatMode")]
+#[serde(rename_all = "snake_case")]
 pub enum RepeatMode {
     Consecutive,
     RepeatAll,
@@ -22,12 +59,78 @@ impl Default for RepeatMode {
     }
 }
 
+impl RepeatMode {
+    /// The mode after this one when cycling through them with win.repeat
+    pub fn next(&self) -> Self {
+        match self {
+            RepeatMode::Consecutive => RepeatMode::RepeatAll,
+            RepeatMode::RepeatAll => RepeatMode::RepeatOne,
+            RepeatMode::RepeatOne => RepeatMode::Consecutive,
+        }
+    }
+}
+
 pub enum PlayerAction {
-    PlaySong(String),
+    /// The song has been saved to the cache at the given uri
+    PlaySong(SongData, String),
+    /// The audio url of the song is known, play it before the download ends
+    StreamSong(SongData, String),
+    /// Fraction of the song that has been downloaded
+    DownloadProgress(SongData, f64),
+    /// Songs were added, removed or reordered
+    QueueChanged,
+    /// The next song has been queued in playbin to follow without a gap
+    GaplessQueued(SongData, String),
+    /// Time to move the volumes of a running crossfade
+    CrossfadeTick,
+    /// The loudness of a cached song has been measured
+    ReplayGain(SongData, ReplayGain),
+    /// The cover of the song has been saved to the cache
+    CoverReady(SongData),
+    PlaybackError(String),
     PlayNext,
     AddSong(SongData),
     UpdatePosition(u64),
     VolumeChanged(f64),
+    TogglePlay,
+    /// Stop and go back to the start of the song
+    Stop,
+    SkipNext,
+    SkipPrevious,
+    Seek(f64),
+    SetVolume(f64),
+    Error(PlayerError),
+}
+
+/// A failure the user should hear about, carrying what is needed to
+/// try again
+pub enum PlayerError {
+    Download(SongData, Error),
+    AddSongs(BiliInput, Error),
+    SyncConfig(Error),
+    /// GStreamer could not play the cached song
+    Playback(SongData, String),
+}
+
+impl PlayerError {
+    /// Retrying is pointless once the video is known to be gone
+    pub fn can_retry(&self) -> bool {
+        match self {
+            PlayerError::Download(_, e) | PlayerError::AddSongs(_, e) => {
+                !e.is_unavailable() && !matches!(e, Error::Offline)
+            }
+            PlayerError::SyncConfig(_) | PlayerError::Playback(..) => true,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            PlayerError::Download(data, e) => format!("无法播放《{}》: {}", data.title(), e),
+            PlayerError::AddSongs(_, e) => format!("添加失败: {}", e),
+            PlayerError::SyncConfig(e) => format!("无法保存播放列表: {}", e),
+            PlayerError::Playback(data, e) => format!("无法播放《{}》: {}", data.title(), e),
+        }
+    }
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -43,41 +146,723 @@ impl Default for PlaybackState {
     }
 }
 
+/// A running crossfade, the backend at `from` fades out while the active
+/// one fades in
+struct Crossfade {
+    from: usize,
+    start: Instant,
+    duration: Duration,
+    source: glib::SourceId,
+}
+
 pub struct AudioPlayer {
     state: PlayerState,
-    backend: gstreamer_player::Player,
+    /// Two players so that one can fade out while the other fades in
+    backends: [gstreamer_player::Player; 2],
+    /// Index of the backend playing the current song, shared with the signal
+    /// handlers which ignore the other one
+    active: Arc<AtomicUsize>,
+    /// The `volume` element each backend uses as audio-filter, the fades
+    /// happen there so that the volume set by the user is left alone
+    fade_volumes: [gst::Element; 2],
+    /// Applies the loudness normalization of the song in each backend
+    gain_volumes: [gst::Element; 2],
+    crossfade: RefCell<Option<Crossfade>>,
     pub queue: Queue,
     pub tx: Arc<Sender<PlayerAction>>,
+    mpris: Option<MprisController>,
+    error_handler: RefCell<Option<Box<dyn Fn(PlayerError)>>>,
+    /// The song currently played from the network instead of the cache
+    streaming: RefCell<Option<SongData>>,
+    stream_failed: Cell<bool>,
+    /// Bumped to cancel the running prefetch
+    prefetch_generation: Arc<AtomicUsize>,
+    /// The song playbin switches to when the current one is about to
+    /// finish, if it is cached by then
+    gapless_next: Arc<Mutex<Option<(SongData, PathBuf)>>>,
+    /// Queued in playbin, becomes current once its uri starts playing
+    gapless_pending: RefCell<Option<(SongData, String)>>,
+    /// The song restored on launch and where to seek once it is loaded
+    resume: RefCell<Option<(SongData, u64)>>,
+    /// Current song, position and volume as of the last periodic save
+    saved_playback: Cell<(Option<u32>, u64, f64)>,
+    /// Where the playlists, playback and song details are kept
+    library: Rc<Library>,
+    /// The song of the last position update and that position, to notice
+    /// when playback passes the listen threshold
+    listen_position: RefCell<Option<(SongData, u64)>>,
+    /// Tells when the network goes away, which turns on offline mode
+    network_monitor: gio::NetworkMonitor,
+}
+
+fn send_download_result(tx: &Sender<PlayerAction>, song_data: SongData, result: Result<String>) {
+    match result {
+        Ok(uri) => {
+            debug!("Download success {}.", uri);
+            tx.send(PlayerAction::PlaySong(song_data, uri)).unwrap();
+        }
+        Err(e) => {
+            warn!("Download {} failed: {}", song_data.title(), e);
+            tx.send(PlayerAction::DownloadProgress(song_data.clone(), 0.0))
+                .unwrap();
+            let error = PlayerError::Download(song_data, e);
+            tx.send(PlayerAction::Error(error)).unwrap();
+        }
+    }
+}
+
+/// Forward the download progress, at most once per percent. The download
+/// is never cancelled.
+fn progress_reporter(
+    tx: Arc<Sender<PlayerAction>>,
+    song_data: SongData,
+) -> impl FnMut(u64, Option<u64>) -> bool {
+    let mut last_percent = None;
+    move |received, total| {
+        if let Some(total) = total.filter(|total| *total > 0) {
+            let percent = (received * 100 / total).min(100);
+            if last_percent != Some(percent) {
+                last_percent = Some(percent);
+                let progress = percent as f64 / 100.0;
+                tx.send(PlayerAction::DownloadProgress(song_data.clone(), progress))
+                    .unwrap();
+            }
+        }
+        true
+    }
+}
+
+/// Bilibili refuses requests for the audio without these headers
+fn set_http_headers(source: &gst::Element) {
+    if source.has_property("user-agent", None) {
+        source.set_property("user-agent", BILIBILI_UA);
+    }
+    if source.has_property("extra-headers", None) {
+        let headers = gst::Structure::builder("extra-headers")
+            .field("Referer", BILIBILI_REFERER)
+            .build();
+        source.set_property("extra-headers", &headers);
+    }
+}
+
+/// A player with its replaygain and fade `volume` elements
+fn create_backend() -> (gstreamer_player::Player, gst::Element, gst::Element) {
+    let dispatcher = gstreamer_player::PlayerGMainContextSignalDispatcher::new(None);
+    let player = gstreamer_player::Player::new(
+        None,
+        Some(&dispatcher.upcast::<gstreamer_player::PlayerSignalDispatcher>()),
+    );
+    let filter = gst::parse_bin_from_description("volume name=gain ! volume name=fade", true)
+        .expect("Missing the volume element");
+    let gain_volume = filter.by_name("gain").unwrap();
+    let fade_volume = filter.by_name("fade").unwrap();
+    player.pipeline().set_property("audio-filter", &filter);
+    (player, gain_volume, fade_volume)
 }
 
 impl AudioPlayer {
+    fn active_index(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    fn backend(&self) -> &gstreamer_player::Player {
+        &self.backends[self.active_index()]
+    }
+
+    /// Set the loudness normalization for the song the backend at `index`
+    /// is about to play
+    fn apply_replay_gain(&self, index: usize, data: &SongData) {
+        let (mode, pre_amp) = settings::read(|s| (s.replay_gain, s.pre_amp));
+        let factor = replaygain::gain_factor(data.replay_gain(), mode, pre_amp);
+        debug!("Replay gain of {}: {:.3}", data.title(), factor);
+        self.gain_volumes[index].set_property("volume", factor);
+    }
+
+    /// Measure the loudness of a cached song which has not been analysed yet
+    fn analyse_song(&self, data: &SongData, path: PathBuf) {
+        let mode = settings::read(|s| s.replay_gain);
+        if data.replay_gain().is_some() || mode == ReplayGainMode::Off {
+            return;
+        }
+        let data = data.clone();
+        let tx = self.tx.clone();
+        std::thread::spawn(move || match replaygain::analyse(&path) {
+            Ok(replay_gain) => {
+                debug!("{}: {:?}", data.title(), replay_gain);
+                tx.send(PlayerAction::ReplayGain(data, replay_gain))
+                    .unwrap();
+            }
+            Err(e) => warn!("Unable to analyse {}: {}", data.title(), e),
+        });
+    }
+
+    /// Store the analysis and update the album gain of the parts which
+    /// share the album of the song
+    fn set_replay_gain(&self, data: &SongData, replay_gain: ReplayGain) {
+        let song = match self.queue.find_song(data) {
+            Some(song) => song,
+            None => return,
+        };
+        song.set_replay_gain(Some(replay_gain));
+
+        if let Some(album) = data.album() {
+            let parts: Vec<(Song, ReplayGain)> = (0..self.queue.n_songs())
+                .filter_map(|pos| self.queue.song_at(pos))
+                .filter(|song| song.song_data().album() == Some(album))
+                .filter_map(|song| {
+                    let replay_gain = song.song_data().replay_gain().cloned()?;
+                    Some((song, replay_gain))
+                })
+                .collect();
+            let tracks: Vec<(ReplayGain, u64)> = parts
+                .iter()
+                .map(|(song, replay_gain)| (*replay_gain, song.duration()))
+                .collect();
+            if let Some((album_gain, album_peak)) = replaygain::album_gain(&tracks) {
+                for (song, replay_gain) in parts {
+                    song.set_replay_gain(Some(ReplayGain {
+                        album_gain: Some(album_gain),
+                        album_peak: Some(album_peak),
+                        ..replay_gain
+                    }));
+                    self.queue.update_song(&song.song_data());
+                }
+            }
+        }
+        self.queue.update_song(&song.song_data());
+    }
+
+    /// How long to crossfade from `current` into `next`, None if they should
+    /// follow each other without a fade. Parts of the same album are often
+    /// one continuous recording.
+    fn crossfade_duration(&self, current: &SongData, next: &SongData) -> Option<Duration> {
+        let seconds = settings::read(|s| s.crossfade);
+        if seconds == 0 || (current.album().is_some() && current.album() == next.album()) {
+            return None;
+        }
+        Some(Duration::from_secs(seconds as u64))
+    }
+
+    fn maybe_start_crossfade(&self, position: u64) {
+        if self.crossfade.borrow().is_some() || !self.state.playing() {
+            return;
+        }
+        let current = match self.state.current_song() {
+            Some(song) => song,
+            None => return,
+        };
+        let next = match self
+            .queue
+            .next_playable_index(self.queue.current_song_index())
+            .and_then(|pos| self.queue.song_at(pos))
+        {
+            Some(song) => song,
+            None => return,
+        };
+        let duration = match self.crossfade_duration(&current.song_data(), &next.song_data()) {
+            Some(duration) => duration,
+            None => return,
+        };
+        if current.duration() == 0 || position + duration.as_secs() < current.duration() {
+            return;
+        }
+        // Only a cached song starts quickly enough to overlap
+        if let Some(uri) = next.uri() {
+            self.start_crossfade(next.song_data(), uri, duration);
+        }
+    }
+
+    fn start_crossfade(&self, next: SongData, uri: String, duration: Duration) {
+        debug!("Crossfade into {} over {:?}", next.title(), duration);
+        let from = self.active_index();
+        let to = 1 - from;
+        self.fade_volumes[to].set_property("volume", 0.0f64);
+        self.apply_replay_gain(to, &next);
+        self.backends[to].set_volume(self.backends[from].volume());
+        self.backends[to].set_uri(Some(uri.as_str()));
+        self.backends[to].play();
+        self.active.store(to, Ordering::SeqCst);
+
+        let tx = self.tx.clone();
+        let source = glib::timeout_add_local(Duration::from_millis(50), move || {
+            tx.send(PlayerAction::CrossfadeTick).unwrap();
+            glib::Continue(true)
+        });
+        self.crossfade.replace(Some(Crossfade {
+            from,
+            start: Instant::now(),
+            duration,
+            source,
+        }));
+        self.continue_with(next);
+    }
+
+    fn crossfade_step(&self) {
+        let (from, progress) = match &*self.crossfade.borrow() {
+            Some(fade) => (
+                fade.from,
+                fade.start.elapsed().as_secs_f64() / fade.duration.as_secs_f64(),
+            ),
+            None => return,
+        };
+        if progress >= 1.0 {
+            self.stop_crossfade();
+            return;
+        }
+        // Equal power curve, the loudness stays about the same during the fade
+        let angle = progress * FRAC_PI_2;
+        self.fade_volumes[from].set_property("volume", angle.cos());
+        self.fade_volumes[1 - from].set_property("volume", angle.sin());
+    }
+
+    /// End the crossfade, or cut it short when the user skips, seeks or pauses
+    fn stop_crossfade(&self) {
+        if let Some(fade) = self.crossfade.take() {
+            fade.source.remove();
+            self.backends[fade.from].stop();
+            for fade_volume in &self.fade_volumes {
+                fade_volume.set_property("volume", 1.0f64);
+            }
+        }
+    }
+
     fn download_song(&self, song: Song) {
-        let song_data = song.song_data();
+        let mut song_data = song.song_data();
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            let progress = progress_reporter(tx.clone(), song_data.clone());
+            let result = song_data.download(progress);
+            send_download_result(&tx, song_data, result);
+        });
+    }
+
+    /// Start playing from the audio url as soon as it is resolved, while
+    /// the same url is saved to the cache. If the stream breaks, the song
+    /// is played again from the cache once the download is done.
+    fn stream_song(&self, song: Song) {
+        let mut song_data = song.song_data();
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            let result = song_data.audio_stream().and_then(|stream| {
+                let action = PlayerAction::StreamSong(song_data.clone(), stream.base_url.clone());
+                tx.send(action).unwrap();
+                let progress = progress_reporter(tx.clone(), song_data.clone());
+                song_data.download_from(&stream, progress)
+            });
+            send_download_result(&tx, song_data, result);
+        });
+    }
+
+    fn cancel_prefetch(&self) {
+        self.prefetch_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Download the songs that come after the current one, so that the
+    /// next track change does not have to wait for the network. Runs until
+    /// the queue changes or another song starts.
+    fn prefetch(&self) {
+        self.cancel_prefetch();
+        if self.queue.offline() {
+            return;
+        }
+        let lookahead = settings::read(|s| s.prefetch);
+        let songs: Vec<SongData> = self
+            .queue
+            .upcoming_songs(lookahead)
+            .into_iter()
+            .filter(|song| song.uri().is_none())
+            .map(|song| song.song_data())
+            .collect();
+        if songs.is_empty() {
+            return;
+        }
+
+        let generation = self.prefetch_generation.clone();
+        let started = generation.load(Ordering::SeqCst);
         let tx = self.tx.clone();
         std::thread::spawn(move || {
-            if let Ok(uri) = song_data.download() {
-                debug!("Download success {}.", uri);
-                tx.send(PlayerAction::PlaySong(uri)).unwrap();
+            let cancelled = || generation.load(Ordering::SeqCst) != started;
+            for mut song_data in songs {
+                if cancelled() {
+                    break;
+                }
+                debug!("Prefetch {}", song_data.title());
+                let mut report = progress_reporter(tx.clone(), song_data.clone());
+                let result =
+                    song_data.download(|received, total| report(received, total) && !cancelled());
+                match result {
+                    Ok(uri) => tx.send(PlayerAction::PlaySong(song_data, uri)).unwrap(),
+                    Err(e) => {
+                        debug!("Prefetch {} stopped: {}", song_data.title(), e);
+                        tx.send(PlayerAction::DownloadProgress(song_data.clone(), 0.0))
+                            .unwrap();
+                        if e.is_unavailable() {
+                            let error = PlayerError::Download(song_data, e);
+                            tx.send(PlayerAction::Error(error)).unwrap();
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Remember the song next_song would return, for the about-to-finish
+    /// handler which can not touch the queue from the streaming thread
+    fn update_gapless_next(&self) {
+        let current = self.state.current_song().map(|song| song.song_data());
+        let next = self
+            .queue
+            .next_playable_index(self.queue.current_song_index())
+            .and_then(|pos| self.queue.song_at(pos))
+            // Crossfading takes over before playbin is about to finish
+            .filter(|song| match &current {
+                Some(current) => self
+                    .crossfade_duration(current, &song.song_data())
+                    .is_none(),
+                None => true,
+            })
+            .map(|song| (song.song_data(), song.cache_path()));
+        *self.gapless_next.lock().unwrap() = next;
+    }
+
+    /// The player keeps reporting the uri it was given, only playbin knows
+    /// when the queued song has actually started
+    fn check_gapless(&self) {
+        let pending = self.gapless_pending.borrow().clone();
+        if let Some((data, uri)) = pending {
+            let current_uri = self
+                .backend()
+                .pipeline()
+                .property::<Option<String>>("current-uri");
+            if current_uri.as_deref() == Some(uri.as_str()) {
+                self.gapless_pending.replace(None);
+                self.continue_with(data);
+            }
+        }
+    }
+
+    /// The backend went on to the next song by itself, after a gapless
+    /// transition or a crossfade, catch the queue up with it
+    fn continue_with(&self, data: SongData) {
+        if let Some(current_song) = self.state.current_song() {
+            current_song.set_playing(false);
+        }
+
+        match self.queue.next_song() {
+            Some(song) if song.song_data() == data => {
+                debug!("Continue with {}", data.title());
+                // Too late for the first moments after a gapless transition,
+                // but parts of an album share their gain in album mode
+                self.apply_replay_gain(self.active_index(), &data);
+                self.streaming.replace(None);
+                self.state.set_current_song(Some(song));
+                self.state.set_playback_state(&PlaybackState::Playing);
+                self.touch_cache(&data);
+                self.update_gapless_next();
+                self.prefetch();
+            }
+            // The queue changed in the meantime, play what it says instead
+            Some(song) => {
+                self.state.set_current_song(Some(song));
+                self.set_playback_state(PlaybackState::Playing);
+            }
+            None => {
+                self.state.set_current_song(None);
+                self.set_playback_state(PlaybackState::Stopped);
+            }
+        }
+    }
+
+    /// Put back the song and volume of the last run, paused; the song is
+    /// loaded when playback is resumed
+    fn restore(&self, playback: &PlaybackData) {
+        self.set_volume(playback.volume);
+        self.state.set_volume(playback.volume);
+        if let Some(song) = self.queue.restore(playback) {
+            self.resume
+                .replace(Some((song.song_data(), playback.position)));
+            self.state.set_current_song(Some(song));
+            self.state.set_position(playback.position);
+            self.state.set_playback_state(&PlaybackState::Paused);
+        }
+        self.saved_playback.set((
+            self.queue.current_song_index(),
+            playback.position,
+            playback.volume,
+        ));
+    }
+
+    /// Seek to where the restored song was left off, once it is loaded
+    fn resume(&self) {
+        let resume = self.resume.take();
+        if let Some((data, position)) = resume {
+            if self.is_current(&data) {
+                self.backend().seek(ClockTime::from_seconds(position));
             }
+        }
+    }
+
+    /// Save the playback if the song, position or volume moved since last time
+    fn save_playback(&self) {
+        let playback = (
+            self.queue.current_song_index(),
+            self.state.position(),
+            self.state.volume(),
+        );
+        if playback != self.saved_playback.get() {
+            self.saved_playback.set(playback);
+            self.queue.save_playback(playback.1, playback.2);
+        }
+    }
+
+    /// Add to the play history once the current song plays past
+    /// LISTEN_THRESHOLD or its middle, seeking past it does not count
+    fn update_listen(&self, pos: u64) {
+        let data = match self.state.current_song() {
+            Some(song) => song.song_data(),
+            None => return,
+        };
+        let threshold = match data.duration() {
+            0 => LISTEN_THRESHOLD,
+            duration => (duration / 2).min(LISTEN_THRESHOLD),
+        };
+        let previous = self.listen_position.replace(Some((data.clone(), pos)));
+        let passed = match previous {
+            Some((previous, last_pos)) if previous == data => {
+                last_pos < threshold && pos >= threshold && pos - last_pos <= 2
+            }
+            _ => false,
+        };
+        if passed {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64);
+            if let Err(e) = self.library.record_play(&data, now) {
+                warn!("Failed to record playing {}: {}", data.title(), e);
+            }
+        }
+    }
+
+    /// Download the cover of the current song unless it is cached
+    fn fetch_cover(&self) {
+        let song = match self.state.current_song() {
+            Some(song) if song.cover().is_none() && !song.unavailable() => song,
+            _ => return,
+        };
+        let mut data = song.song_data();
+        let tx = self.tx.clone();
+        std::thread::spawn(move || match data.download_cover() {
+            Ok(_) => tx.send(PlayerAction::CoverReady(data)).unwrap(),
+            Err(e) => warn!("Unable to fetch the cover of {}: {}", data.title(), e),
         });
     }
 
+    /// Where the playlists and the play history are kept
+    pub fn library(&self) -> &Library {
+        &self.library
+    }
+
+    /// The songs whose files must stay: the current one and the ones being
+    /// prefetched, at least the next one
+    fn cache_keep(&self) -> Vec<SongData> {
+        let lookahead = settings::read(|s| s.prefetch).max(1);
+        let mut keep: Vec<SongData> = self
+            .queue
+            .upcoming_songs(lookahead)
+            .iter()
+            .map(|song| song.song_data())
+            .collect();
+        if let Some(song) = self.state.current_song() {
+            keep.push(song.song_data());
+        }
+        keep
+    }
+
+    /// Note that the cached file of `data` was used, which may push other
+    /// files out of the cache
+    fn touch_cache(&self, data: &SongData) {
+        if let Err(e) = cache::touch(&self.library, data) {
+            warn!("Unable to track the cached {}: {}", data.title(), e);
+        }
+        self.apply_cache_limit();
+    }
+
+    /// Evict the least recently used songs until the cache is within the
+    /// limit of the settings
+    pub fn apply_cache_limit(&self) {
+        let limit = settings::read(|s| s.cache_limit);
+        if limit == 0 {
+            return;
+        }
+        match cache::evict(&self.library, limit * MIB, &self.cache_keep()) {
+            Ok(evicted) => self.notify_cached(&evicted),
+            Err(e) => warn!("Failed to evict songs from the cache: {}", e),
+        }
+    }
+
+    /// The cached songs, most recently used first
+    pub fn cache_entries(&self) -> Vec<CacheEntry> {
+        self.library.cache_entries().unwrap_or_else(|e| {
+            warn!("Unable to read the cache entries: {}", e);
+            Vec::new()
+        })
+    }
+
+    /// Bytes taken by the cached covers, on top of the cache entries
+    pub fn covers_size(&self) -> u64 {
+        cache::covers_size()
+    }
+
+    /// Keep the cached file of `data` for offline use, or let it be evicted.
+    /// Only songs which are cached can be pinned.
+    pub fn set_pinned(&self, data: &SongData, pinned: bool) {
+        if pinned && !data.cache_path().exists() {
+            return;
+        }
+        if let Err(e) = self.library.set_pinned(data, pinned) {
+            warn!("Unable to pin {}: {}", data.title(), e);
+        }
+        if !pinned {
+            self.apply_cache_limit();
+        }
+    }
+
+    /// Delete the cached file of `data`, unless it is playing
+    pub fn remove_cached(&self, data: &SongData) {
+        if self.is_current(data) {
+            return;
+        }
+        cache::remove_file(data);
+        if let Err(e) = self.library.forget_cache(data) {
+            warn!("Failed to forget the cached {}: {}", data.title(), e);
+        }
+        self.notify_cached(std::slice::from_ref(data));
+    }
+
+    /// Delete the cached files of songs removed from the queue, pinned
+    /// songs stay for offline use
+    pub fn drop_cache(&self, songs: &[SongData]) {
+        let pinned: Vec<SongData> = self
+            .cache_entries()
+            .into_iter()
+            .filter(|entry| entry.pinned)
+            .map(|entry| entry.song)
+            .collect();
+        for data in songs.iter().filter(|data| !pinned.contains(data)) {
+            self.remove_cached(data);
+        }
+    }
+
+    /// Delete every cached file but the one playing
+    pub fn clear_cache(&self) {
+        let keep: Vec<SongData> = self
+            .state
+            .current_song()
+            .map(|song| song.song_data())
+            .into_iter()
+            .collect();
+        match cache::clear(&self.library, &keep) {
+            Ok(removed) => self.notify_cached(&removed),
+            Err(e) => warn!("Failed to clear the cache: {}", e),
+        }
+    }
+
+    /// Let the queue know that the files of `songs` came or went
+    fn notify_cached(&self, songs: &[SongData]) {
+        for data in songs {
+            if let Some(song) = self.queue.find_song(data) {
+                song.notify("cached");
+            }
+        }
+        if !songs.is_empty() {
+            self.update_gapless_next();
+        }
+    }
+
+    /// Play only from the cache whether the network is there or not
+    pub fn set_offline(&self, offline: bool) {
+        settings::update(|s| s.offline = offline);
+        self.update_offline();
+    }
+
+    /// Go offline when asked to or when there is no network, and back
+    fn update_offline(&self) {
+        let offline = settings::read(|s| s.offline) || !self.network_monitor.is_network_available();
+        if offline == self.queue.offline() {
+            return;
+        }
+        debug!("Offline: {}", offline);
+        bilibili::set_offline(offline);
+        self.queue.set_offline(offline);
+        self.update_gapless_next();
+        if !offline {
+            let cached = self.state.current_song().and_then(|song| song.uri());
+            if cached.is_some() {
+                self.prefetch();
+            }
+            return;
+        }
+
+        self.cancel_prefetch();
+        // Whatever was being downloaded or streamed will not arrive
+        let waiting = self
+            .state
+            .current_song()
+            .map_or(false, |song| song.uri().is_none());
+        if waiting && self.state.playing() {
+            self.skip_next();
+        }
+    }
+
+    fn is_current(&self, data: &SongData) -> bool {
+        self.state
+            .current_song()
+            .map_or(false, |song| song.song_data() == *data)
+    }
+
+    fn is_streaming(&self, data: &SongData) -> bool {
+        !self.stream_failed.get() && self.streaming.borrow().as_ref() == Some(data)
+    }
+
     fn set_playback_state(&self, state: PlaybackState) {
         match state {
             PlaybackState::Playing => {
                 if let Some(song) = self.state.current_song() {
+                    self.stop_crossfade();
+                    self.streaming.replace(None);
+                    self.gapless_pending.replace(None);
+                    self.update_gapless_next();
+                    self.cancel_prefetch();
                     if let Some(uri) = song.uri() {
-                        self.backend.set_uri(Some(uri.as_str()));
+                        self.touch_cache(&song.song_data());
+                        self.analyse_song(&song.song_data(), song.cache_path());
+                        self.apply_replay_gain(self.active_index(), &song.song_data());
+                        self.backend().set_uri(Some(uri.as_str()));
                         debug!("{}", uri);
-                        self.backend.play();
+                        self.backend().play();
+                        self.resume();
+                        self.prefetch();
+                    } else if self.queue.offline() {
+                        debug!("{} is not cached, skip it offline", song.title());
+                        self.skip_next();
+                        return;
+                    } else if settings::read(|s| s.streaming) {
+                        self.backend().stop();
+                        self.stream_song(song);
                     } else {
                         self.state.set_playback_state(&PlaybackState::Stopped);
                         self.download_song(song);
                     }
                 }
             }
-            PlaybackState::Paused => self.backend.pause(),
-            PlaybackState::Stopped => self.backend.stop(),
+            PlaybackState::Paused => {
+                self.stop_crossfade();
+                self.backend().pause();
+            }
+            PlaybackState::Stopped => {
+                self.stop_crossfade();
+                self.backend().stop();
+            }
         }
         self.state.set_playback_state(&state);
     }
@@ -95,7 +880,7 @@ impl AudioPlayer {
             self.state.set_current_song(Some(song));
             self.set_playback_state(PlaybackState::Playing);
         } else {
-            self.backend.set_uri(None);
+            self.backend().set_uri(None);
             self.state.set_current_song(None);
             self.set_playback_state(PlaybackState::Stopped);
         }
@@ -125,7 +910,7 @@ impl AudioPlayer {
         if let Some(prev_song) = self.queue.previous_song() {
             prev_song.set_playing(true);
             self.state.set_current_song(Some(prev_song));
-            self.backend.seek(ClockTime::from_seconds(0));
+            self.backend().seek(ClockTime::from_seconds(0));
             self.set_playback_state(PlaybackState::Playing);
         }
     }
@@ -136,17 +921,100 @@ impl AudioPlayer {
                 let song = Song::new(data);
                 self.queue.add_song(&song);
             }
-            PlayerAction::PlaySong(uri) => {
-                let was_playing = self.state.playing();
-                if was_playing {
-                    self.backend.stop();
+            PlayerAction::PlaySong(data, uri) => {
+                if let Some(song) = self.queue.find_song(&data) {
+                    if song.song_data().stream() != data.stream() {
+                        song.set_stream(data.stream().cloned());
+                        self.queue.update_song(&song.song_data());
+                        self.update_gapless_next();
+                    }
+                    self.analyse_song(&song.song_data(), song.cache_path());
+                }
+                self.touch_cache(&data);
+                self.notify_cached(std::slice::from_ref(&data));
+                if !self.is_current(&data) {
+                    debug!("{} is no longer current", data.title());
+                } else if self.is_streaming(&data) {
+                    debug!("{} cached while streaming", data.title());
+                    self.prefetch();
+                } else {
+                    let was_playing = self.state.playing();
+                    if was_playing {
+                        self.backend().stop();
+                    }
+                    debug!("{}", uri);
+                    self.streaming.replace(None);
+                    self.apply_replay_gain(self.active_index(), &data);
+                    self.backend().set_uri(Some(uri.as_str()));
+                    self.backend().play();
+                    self.resume();
+                    self.prefetch();
+                }
+            }
+            PlayerAction::StreamSong(data, url) => {
+                if self.is_current(&data) {
+                    debug!("Streaming {}", url);
+                    self.apply_replay_gain(self.active_index(), &data);
+                    self.streaming.replace(Some(data));
+                    self.stream_failed.set(false);
+                    self.backend().set_uri(Some(url.as_str()));
+                    self.backend().play();
+                    self.resume();
+                }
+            }
+            PlayerAction::CoverReady(data) => {
+                if let Some(song) = self.queue.find_song(&data) {
+                    song.set_cover_url(data.cover_url().map(str::to_string));
+                    self.queue.update_song(&song.song_data());
+                }
+                if self.is_current(&data) {
+                    self.state.notify("cover");
+                }
+            }
+            PlayerAction::ReplayGain(data, replay_gain) => {
+                self.set_replay_gain(&data, replay_gain);
+                self.update_gapless_next();
+            }
+            PlayerAction::GaplessQueued(data, uri) => {
+                self.gapless_pending.replace(Some((data, uri)));
+            }
+            PlayerAction::QueueChanged => {
+                self.update_gapless_next();
+                // Start over with the new upcoming songs, unless the current
+                // song is still downloading and will start the prefetch itself
+                let cached = self.state.current_song().and_then(|song| song.uri());
+                if cached.is_some() {
+                    self.prefetch();
+                } else {
+                    self.cancel_prefetch();
+                }
+            }
+            PlayerAction::DownloadProgress(data, progress) => {
+                if let Some(song) = self.queue.find_song(&data) {
+                    song.set_download_progress(progress);
+                }
+            }
+            PlayerAction::PlaybackError(message) => {
+                warn!("Playback error: {}", message);
+                if self.streaming.borrow().is_some() {
+                    // The download keeps going, PlaySong picks the song up again
+                    self.stream_failed.set(true);
+                } else if let Some(song) = self.state.current_song() {
+                    let data = song.song_data();
+                    self.queue.mark_failed(&data);
+                    self.skip_next();
+                    let error = PlayerError::Playback(data, message);
+                    self.tx.send(PlayerAction::Error(error)).unwrap();
                 }
-                debug!("{}", uri);
-                self.backend.set_uri(Some(uri.as_str()));
-                self.backend.play();
             }
             PlayerAction::UpdatePosition(pos) => {
+                self.check_gapless();
                 self.state.set_position(pos);
+                self.update_listen(pos);
+                self.maybe_start_crossfade(pos);
+            }
+            PlayerAction::CrossfadeTick => {
+                self.crossfade_step();
             }
             PlayerAction::PlayNext => {
                 self.skip_next();
@@ -154,45 +1022,172 @@ impl AudioPlayer {
             PlayerAction::VolumeChanged(volume) => {
                 self.state.set_volume(volume);
             }
+            PlayerAction::TogglePlay => {
+                self.toggle_play();
+            }
+            PlayerAction::Stop => {
+                self.set_playback_state(PlaybackState::Stopped);
+                self.state.set_position(0);
+            }
+            PlayerAction::SkipNext => {
+                self.skip_next();
+            }
+            PlayerAction::SkipPrevious => {
+                self.skip_previous();
+            }
+            PlayerAction::Seek(percent) => {
+                self.seek(percent);
+            }
+            PlayerAction::SetVolume(volume) => {
+                self.set_volume(volume);
+            }
+            PlayerAction::Error(error) => {
+                if let PlayerError::Download(data, e) = &error {
+                    if self.is_streaming(data) {
+                        // Playback is fine, the song just did not make it into the cache
+                        warn!("Caching {} failed: {}", data.title(), e);
+                        return glib::Continue(true);
+                    }
+                    if e.is_unavailable() {
+                        self.queue.mark_unavailable(data);
+                        if self.is_current(data) {
+                            self.skip_next();
+                        }
+                    }
+                }
+                if let Some(handler) = &*self.error_handler.borrow() {
+                    handler(error);
+                } else {
+                    warn!("{}", error.message());
+                }
+            }
         }
         glib::Continue(true)
     }
 
     fn setup_signal(&self) {
+        for (index, backend) in self.backends.iter().enumerate() {
+            self.setup_backend_signal(index, backend);
+        }
+
+        let tx = self.tx.clone();
+        self.queue.model().connect_items_changed(move |_, _, _, _| {
+            tx.send(PlayerAction::QueueChanged).unwrap();
+        });
+
+        let tx = self.tx.clone();
+        self.queue
+            .connect_notify_local(Some("repeat-mode"), move |_, _| {
+                tx.send(PlayerAction::QueueChanged).unwrap();
+            });
+    }
+
+    /// Signals of the backend at `index` only count while it is the active one
+    fn setup_backend_signal(&self, index: usize, backend: &gstreamer_player::Player) {
         let tx = self.tx.clone();
-        self.backend.connect_position_updated(move |_, clock| {
-            if let Some(clock) = clock {
+        let active = self.active.clone();
+        backend.connect_position_updated(move |_, clock| {
+            if let Some(clock) = clock.filter(|_| active.load(Ordering::SeqCst) == index) {
                 tx.send(PlayerAction::UpdatePosition(clock.seconds()))
                     .unwrap();
             }
         });
 
         let tx = self.tx.clone();
-        self.backend.connect_end_of_stream(move |_| {
-            tx.send(PlayerAction::PlayNext).unwrap();
+        let active = self.active.clone();
+        backend.connect_end_of_stream(move |_| {
+            if active.load(Ordering::SeqCst) == index {
+                tx.send(PlayerAction::PlayNext).unwrap();
+            }
+        });
+
+        let tx = self.tx.clone();
+        let active = self.active.clone();
+        backend.connect_volume_changed(move |player| {
+            if active.load(Ordering::SeqCst) == index {
+                let volume = player.volume();
+                tx.send(PlayerAction::VolumeChanged(volume)).unwrap();
+            }
+        });
+
+        let tx = self.tx.clone();
+        let active = self.active.clone();
+        backend.connect_error(move |_, error| {
+            if active.load(Ordering::SeqCst) == index {
+                tx.send(PlayerAction::PlaybackError(error.to_string()))
+                    .unwrap();
+            }
         });
 
+        // Runs in the streaming thread shortly before the current song ends.
+        // Setting the uri here makes playbin go on without a gap, and no
+        // end-of-stream is emitted.
         let tx = self.tx.clone();
-        self.backend.connect_volume_changed(move |player| {
-            let volume = player.volume();
-            tx.send(PlayerAction::VolumeChanged(volume)).unwrap();
+        let active = self.active.clone();
+        let gapless_next = self.gapless_next.clone();
+        backend
+            .pipeline()
+            .connect("about-to-finish", false, move |values| {
+                if active.load(Ordering::SeqCst) != index {
+                    return None;
+                }
+                let playbin = values[0].get::<gst::Element>().ok()?;
+                let (data, path) = gapless_next.lock().unwrap().take()?;
+                if !path.exists() {
+                    return None;
+                }
+                let uri = glib::filename_to_uri(&path, None).ok()?.to_string();
+                debug!("Queue {} for gapless playback", uri);
+                tx.send(PlayerAction::GaplessQueued(data, uri.clone()))
+                    .unwrap();
+                playbin.set_property("uri", &uri);
+                None
+            });
+
+        backend.pipeline().connect("source-setup", false, |values| {
+            if let Ok(source) = values[1].get::<gst::Element>() {
+                set_http_headers(&source);
+            }
+            None
         });
     }
 
     pub fn new() -> Rc<Self> {
         let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
 
-        let dispatcher = gstreamer_player::PlayerGMainContextSignalDispatcher::new(None);
-        let player = gstreamer_player::Player::new(
-            None,
-            Some(&dispatcher.upcast::<gstreamer_player::PlayerSignalDispatcher>()),
-        );
+        let (player, gain_volume, fade_volume) = create_backend();
+        let (fade_player, fade_player_gain, fade_player_volume) = create_backend();
+
+        let tx = Arc::new(tx);
+        let mpris = match MprisController::new(tx.clone()) {
+            Ok(mpris) => Some(mpris),
+            Err(e) => {
+                warn!("Unable to register MPRIS service: {}", e);
+                None
+            }
+        };
 
         let audio_player = Rc::new(Self {
-            backend: player,
+            backends: [player, fade_player],
+            active: Arc::new(AtomicUsize::new(0)),
+            fade_volumes: [fade_volume, fade_player_volume],
+            gain_volumes: [gain_volume, fade_player_gain],
+            crossfade: RefCell::new(None),
             state: PlayerState::default(),
             queue: Queue::default(),
-            tx: Arc::new(tx),
+            tx,
+            mpris,
+            error_handler: RefCell::new(None),
+            streaming: RefCell::new(None),
+            stream_failed: Cell::new(false),
+            prefetch_generation: Arc::new(AtomicUsize::new(0)),
+            gapless_next: Arc::new(Mutex::new(None)),
+            gapless_pending: RefCell::new(None),
+            resume: RefCell::new(None),
+            saved_playback: Cell::new((None, 0, 1.0)),
+            library: Rc::new(Library::open_default()),
+            listen_position: RefCell::new(None),
+            network_monitor: gio::NetworkMonitor::default(),
         });
 
         rx.attach(
@@ -200,12 +1195,46 @@ impl AudioPlayer {
             clone!(@strong audio_player as this => move |action| this.clone().process_action(action))
         );
 
-        if let Ok(data) = parse_config() {
-            audio_player.queue.init(data);
+        audio_player.queue.set_sender(audio_player.tx.clone());
+        audio_player.queue.set_library(audio_player.library.clone());
+        audio_player.queue.load_playlists();
+        audio_player.restore(&audio_player.library.playback().unwrap_or_default());
+        let (repeat_mode, shuffle) = settings::read(|s| (s.repeat_mode, s.shuffle));
+        audio_player.queue.set_repeat_mode(repeat_mode);
+        audio_player.queue.set_shuffled(shuffle);
+        if let Err(e) = cache::migrate(&audio_player.library) {
+            warn!("Unable to move the cache to the new file names: {}", e);
         }
+        if let Err(e) = cache::sync(&audio_player.library) {
+            warn!("Unable to scan the cache: {}", e);
+        }
+        audio_player.apply_cache_limit();
 
         audio_player.setup_signal();
 
+        audio_player.network_monitor.connect_network_changed(
+            clone!(@weak audio_player as this => move |_, _| this.update_offline()),
+        );
+        audio_player.update_offline();
+
+        glib::timeout_add_seconds_local(
+            SAVE_INTERVAL,
+            clone!(@weak audio_player as this => @default-return glib::Continue(false), move || {
+                this.save_playback();
+                glib::Continue(true)
+            }),
+        );
+
+        audio_player.state.connect_notify_local(
+            Some("song"),
+            clone!(@weak audio_player as this => move |_, _| this.fetch_cover()),
+        );
+
+        if let Some(mpris) = &audio_player.mpris {
+            mpris.bind_state(&audio_player.state);
+            mpris.bind_queue(&audio_player.queue);
+        }
+
         audio_player
     }
 
@@ -217,24 +1246,62 @@ impl AudioPlayer {
         &self.state
     }
 
+    /// Load another playlist into the queue, the current song keeps playing
+    pub fn switch_playlist(&self, index: u32) {
+        self.queue.switch_playlist(index);
+        if let Some(song) = self.queue.current_song() {
+            song.set_playing(self.state.playing());
+        }
+    }
+
+    /// Called for every PlayerAction::Error
+    pub fn connect_error<F: Fn(PlayerError) + 'static>(&self, handler: F) {
+        self.error_handler.replace(Some(Box::new(handler)));
+    }
+
+    /// Play a song of the queue again after it failed to download or play
+    pub fn retry_song(&self, data: &SongData) {
+        for pos in 0..self.queue.n_songs() {
+            if let Some(song) = self.queue.song_at(pos) {
+                if song.song_data() == *data {
+                    song.set_failed(false);
+                    if Some(pos) == self.queue.current_song_index() {
+                        self.set_playback_state(PlaybackState::Playing);
+                    } else {
+                        self.skip_to(pos);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
     pub fn toggle_play(&self) {
         if self.state.playing() {
-            self.backend.pause();
+            self.stop_crossfade();
+            self.backend().pause();
             self.state.set_playback_state(&PlaybackState::Paused);
+        } else if self.backend().uri().is_none() {
+            // The song restored on launch has not been loaded yet
+            self.set_playback_state(PlaybackState::Playing);
         } else {
-            self.backend.play();
+            self.backend().play();
             self.state.set_playback_state(&PlaybackState::Playing);
         }
     }
 
     pub fn set_volume(&self, volume: f64) {
-        self.backend.set_volume(volume);
+        for backend in &self.backends {
+            backend.set_volume(volume);
+        }
     }
 
     pub fn seek(&self, percent: f64) {
         if let Some(song) = self.state.current_song() {
+            self.stop_crossfade();
             let seek_time = percent * (song.duration() as f64);
-            self.backend.seek(ClockTime::from_seconds(seek_time as u64));
+            self.backend()
+                .seek(ClockTime::from_seconds(seek_time as u64));
         }
     }
 }
@@ -1,25 +1,49 @@
-use gtk::{gio, glib, prelude::*, subclass::prelude::*};
-
-use crate::bilibili::data::write_config;
-
-use super::{song::Song, RepeatMode, SongData};
+use std::{collections::HashSet, rc::Rc, sync::Arc};
+
+use gtk::{
+    gio,
+    glib::{self, Sender},
+    prelude::*,
+    subclass::prelude::*,
+};
+use log::warn;
+
+use crate::{
+    bilibili::{
+        data::{PlaybackData, DEFAULT_PLAYLIST},
+        Result,
+    },
+    library::Library,
+};
+
+use super::{song::Song, PlayerAction, PlayerError, RepeatMode, SongData};
 
 mod imp {
-    use std::cell::Cell;
+    use std::cell::{Cell, RefCell};
 
     use gstreamer::glib::once_cell::sync::Lazy;
-    use gtk::glib::{ParamFlags, ParamSpec, ParamSpecEnum, ParamSpecObject, ParamSpecUInt};
+    use gtk::glib::{
+        ParamFlags, ParamSpec, ParamSpecBoolean, ParamSpecEnum, ParamSpecObject, ParamSpecUInt,
+    };
 
     use crate::audio::{shuffle::ShuffleListModel, song::Song};
 
     use super::*;
 
-    #[derive(Default)]
     pub struct Queue {
         pub store: gio::ListStore,
         pub repeat_mode: Cell<RepeatMode>,
         pub current_pos: Cell<Option<u32>>,
         pub model: ShuffleListModel,
+        pub tx: RefCell<Option<Arc<Sender<PlayerAction>>>>,
+        /// Names of the playlists, the active one is what the queue holds
+        pub playlists: gtk::StringList,
+        /// Library ids of the playlists
+        pub playlist_ids: RefCell<Vec<i64>>,
+        pub library: RefCell<Option<Rc<Library>>>,
+        pub active_playlist: Cell<u32>,
+        /// Only the cached songs can be played
+        pub offline: Cell<bool>,
     }
 
     #[glib::object_subclass]
@@ -36,6 +60,12 @@ mod imp {
                 repeat_mode: Cell::new(RepeatMode::default()),
                 current_pos: Cell::new(None),
                 model,
+                tx: RefCell::new(None),
+                playlists: gtk::StringList::new(&[DEFAULT_PLAYLIST]),
+                playlist_ids: RefCell::new(Vec::new()),
+                library: RefCell::new(None),
+                active_playlist: Cell::new(0),
+                offline: Cell::new(false),
             }
         }
     }
@@ -60,6 +90,17 @@ mod imp {
                         ParamFlags::READABLE,
                     ),
                     ParamSpecUInt::new("n-songs", "", "", 0, u32::MAX, 0, ParamFlags::READABLE),
+                    ParamSpecBoolean::new("shuffled", "", "", false, ParamFlags::READABLE),
+                    ParamSpecUInt::new(
+                        "active-playlist",
+                        "",
+                        "",
+                        0,
+                        u32::MAX,
+                        0,
+                        ParamFlags::READABLE,
+                    ),
+                    ParamSpecBoolean::new("offline", "", "", false, ParamFlags::READABLE),
                 ]
             });
             PROPERTIES.as_ref()
@@ -70,6 +111,9 @@ mod imp {
                 "current" => obj.current_song().to_value(),
                 "repeat-mode" => self.repeat_mode.get().to_value(),
                 "n-songs" => self.store.n_items().to_value(),
+                "shuffled" => obj.shuffled().to_value(),
+                "active-playlist" => self.active_playlist.get().to_value(),
+                "offline" => self.offline.get().to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -129,6 +173,51 @@ impl Queue {
         self.imp().current_pos.get()
     }
 
+    pub fn repeat_mode(&self) -> RepeatMode {
+        self.imp().repeat_mode.get()
+    }
+
+    pub fn set_repeat_mode(&self, repeat_mode: RepeatMode) {
+        if self.imp().repeat_mode.replace(repeat_mode) != repeat_mode {
+            self.notify("repeat-mode");
+        }
+    }
+
+    pub fn shuffled(&self) -> bool {
+        self.imp().model.shuffled()
+    }
+
+    /// Shuffle the queue with the current song first, or go back to the
+    /// original order. The current song stays current either way.
+    pub fn set_shuffled(&self, shuffled: bool) {
+        if shuffled == self.shuffled() {
+            return;
+        }
+
+        let current = self.current_song();
+        let model = &self.imp().model;
+        if shuffled {
+            let store = &self.imp().store;
+            let first = current.as_ref().and_then(|song| {
+                (0..store.n_items()).find(|pos| {
+                    let item = store.item(*pos).unwrap().downcast::<Song>().unwrap();
+                    item.equals(song)
+                })
+            });
+            model.reshuffle_with_first(first);
+        } else {
+            model.unshuffle();
+        }
+
+        // current_pos is a position of the shuffled model
+        if let Some(song) = current {
+            let pos = (0..self.n_songs())
+                .find(|pos| self.song_at(*pos).map_or(false, |item| item.equals(&song)));
+            self.imp().current_pos.set(pos);
+        }
+        self.notify("shuffled");
+    }
+
     pub fn model(&self) -> &gio::ListModel {
         self.imp().model.as_ref()
     }
@@ -144,7 +233,7 @@ impl Queue {
 
         self.imp().store.append(song);
         self.notify("n-songs");
-        self.sync_config();
+        self.save(|library, active| library.add_songs(active, &[song.song_data()]));
     }
 
     pub fn add_songs(&self, songs: &Vec<Song>) {
@@ -158,7 +247,8 @@ impl Queue {
         if is_shuffled {
             self.imp().model.reshuffle();
         }
-        self.sync_config();
+        let data: Vec<SongData> = songs.iter().map(|song| song.song_data()).collect();
+        self.save(|library, active| library.add_songs(active, &data));
     }
 
     pub fn init(&self, data: Vec<SongData>) {
@@ -177,67 +267,382 @@ impl Queue {
         self.song_at(pos)
     }
 
+    /// Load the playlists of the library, the active one into the queue
+    pub fn load_playlists(&self) {
+        let imp = self.imp();
+        let loaded = self.save(|library, _| {
+            let mut playlists = library.playlists()?;
+            if playlists.is_empty() {
+                let id = library.create_playlist(DEFAULT_PLAYLIST)?;
+                playlists.push((id, DEFAULT_PLAYLIST.to_string()));
+            }
+            let active_id = library.active_playlist()?;
+            let active = playlists
+                .iter()
+                .position(|(id, _)| Some(*id) == active_id)
+                .unwrap_or(0);
+            let songs = library.playlist_songs(playlists[active].0)?;
+            Ok((playlists, active, songs))
+        });
+        let (playlists, active, songs) = match loaded {
+            Some(loaded) => loaded,
+            None => return,
+        };
+
+        let names: Vec<&str> = playlists.iter().map(|(_, name)| name.as_str()).collect();
+        imp.playlists.splice(0, imp.playlists.n_items(), &names);
+        imp.playlist_ids
+            .replace(playlists.iter().map(|(id, _)| *id).collect());
+        self.init(songs);
+        imp.active_playlist.set(active as u32);
+        self.notify("active-playlist");
+    }
+
+    /// Names of the playlists, for the playlist switcher
+    pub fn playlists(&self) -> gio::ListModel {
+        self.imp().playlists.clone().upcast()
+    }
+
+    pub fn active_playlist(&self) -> u32 {
+        self.imp().active_playlist.get()
+    }
+
+    pub fn playlist_name(&self, index: u32) -> Option<String> {
+        self.imp()
+            .playlists
+            .string(index)
+            .map(|name| name.to_string())
+    }
+
+    fn playlist_id(&self, index: u32) -> Option<i64> {
+        self.imp()
+            .playlist_ids
+            .borrow()
+            .get(index as usize)
+            .copied()
+    }
+
+    fn push_playlist(&self, name: &str, id: i64) -> u32 {
+        let imp = self.imp();
+        imp.playlist_ids.borrow_mut().push(id);
+        imp.playlists.append(name);
+        imp.playlists.n_items() - 1
+    }
+
+    /// Add an empty playlist, returns its index
+    pub fn create_playlist(&self, name: &str) -> Option<u32> {
+        let id = self.save(|library, _| library.create_playlist(name))?;
+        Some(self.push_playlist(name, id))
+    }
+
+    /// Add a copy of the playlist at `index`, returns the index of the copy
+    pub fn duplicate_playlist(&self, index: u32) -> Option<u32> {
+        let source = self.playlist_id(index)?;
+        let name = format!("{} 副本", self.playlist_name(index)?);
+        let id = self.save(|library, _| library.duplicate_playlist(source, &name))?;
+        Some(self.push_playlist(&name, id))
+    }
+
+    pub fn rename_playlist(&self, index: u32, name: &str) {
+        if let Some(id) = self.playlist_id(index) {
+            if self
+                .save(|library, _| library.rename_playlist(id, name))
+                .is_some()
+            {
+                self.imp().playlists.splice(index, 1, &[name]);
+            }
+        }
+    }
+
+    /// Remove the playlist at `index`, the last one is kept; when the
+    /// active playlist goes the one before it takes over
+    pub fn delete_playlist(&self, index: u32) -> bool {
+        let imp = self.imp();
+        let n_playlists = imp.playlists.n_items();
+        let id = match self.playlist_id(index) {
+            Some(id) if n_playlists > 1 => id,
+            _ => return false,
+        };
+
+        if self
+            .save(|library, _| library.delete_playlist(id))
+            .is_none()
+        {
+            return false;
+        }
+        if index == self.active_playlist() {
+            self.switch_playlist(if index == 0 { 1 } else { index - 1 });
+        }
+        imp.playlists.remove(index);
+        imp.playlist_ids.borrow_mut().remove(index as usize);
+        if index < self.active_playlist() {
+            imp.active_playlist.set(self.active_playlist() - 1);
+            self.notify("active-playlist");
+        }
+        true
+    }
+
+    /// Load the playlist at `index` into the queue; the song that is
+    /// playing stays current if it is in the playlist too
+    pub fn switch_playlist(&self, index: u32) {
+        let imp = self.imp();
+        if index == self.active_playlist() {
+            return;
+        }
+        let id = match self.playlist_id(index) {
+            Some(id) => id,
+            None => return,
+        };
+        let songs = match self.save(|library, _| {
+            library.set_active_playlist(id)?;
+            library.playlist_songs(id)
+        }) {
+            Some(songs) => songs,
+            None => return,
+        };
+
+        let current = self.current_song().map(|song| song.song_data());
+        self.init(songs);
+        imp.active_playlist.set(index);
+        if self.shuffled() {
+            imp.model.reshuffle();
+        }
+
+        let pos = current.and_then(|data| {
+            (0..self.n_songs()).find(|pos| {
+                self.song_at(*pos)
+                    .map_or(false, |song| song.song_data() == data)
+            })
+        });
+        imp.current_pos.set(pos);
+        self.notify("current");
+        self.notify("n-songs");
+        self.notify("active-playlist");
+    }
+
+    /// Add songs to any playlist, skipping the ones already in it; returns
+    /// how many were added
+    pub fn add_to_playlist(&self, index: u32, songs: &[SongData]) -> usize {
+        if index == self.active_playlist() {
+            let known = (0..self.n_songs())
+                .filter_map(|pos| self.song_at(pos))
+                .map(|song| (song.bvid(), song.cid()))
+                .collect();
+            let songs: Vec<Song> = new_songs(songs, known).into_iter().map(Song::new).collect();
+            self.add_songs(&songs);
+            songs.len()
+        } else if let Some(id) = self.playlist_id(index) {
+            self.save(|library, _| library.add_songs(id, songs))
+                .unwrap_or(0)
+        } else {
+            0
+        }
+    }
+
+    /// Pick up where the last run left off: the shuffle order and the
+    /// current song, which is returned
+    pub fn restore(&self, playback: &PlaybackData) -> Option<Song> {
+        let n = self.imp().store.n_items();
+        if let Some(order) = &playback.shuffle {
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            if sorted.into_iter().eq(0..n) {
+                self.imp().model.set_order(order.clone());
+            }
+        }
+
+        let (bvid, cid) = playback.current.as_ref()?;
+        let pos = (0..self.n_songs()).find(|pos| {
+            self.song_at(*pos)
+                .map_or(false, |song| song.bvid() == *bvid && song.cid() == *cid)
+        })?;
+        self.skip_song(pos)
+    }
+
+    /// Save where the player is, along with the current song and the
+    /// shuffle order
+    pub fn save_playback(&self, position: u64, volume: f64) {
+        let playback = PlaybackData {
+            current: self.current_song().map(|song| (song.bvid(), song.cid())),
+            position,
+            volume,
+            shuffle: self.imp().model.order(),
+        };
+        self.save(|library, _| library.set_playback(&playback));
+    }
+
+    /// Save the changes to a song, e.g. its stream or loudness
+    pub fn update_song(&self, data: &SongData) {
+        self.save(|library, _| library.update_song(data));
+    }
+
+    /// The songs in the order they were added, the shuffle order is saved
+    /// separately
     fn to_vec(&self) -> Vec<SongData> {
         let mut v: Vec<SongData> = Vec::new();
-        let n = self.model().n_items();
-        for i in 0..n {
-            let obj = self.model().item(i).unwrap();
+        let store = &self.imp().store;
+        for i in 0..store.n_items() {
+            let obj = store.item(i).unwrap();
             let song = obj.downcast_ref::<Song>().unwrap();
             v.push(song.imp().data.borrow().clone());
         }
         v
     }
 
-    fn sync_config(&self) {
-        let data: Vec<SongData> = self.to_vec();
-        write_config(data).unwrap();
+    /// Where failures to save the queue are reported
+    pub fn set_sender(&self, tx: Arc<Sender<PlayerAction>>) {
+        self.imp().tx.replace(Some(tx));
     }
 
-    pub fn previous_song(&self) -> Option<Song> {
-        if let Some(current_pos) = self.imp().current_pos.get() {
-            if current_pos > 0 {
-                let prev = current_pos - 1;
-                self.imp().current_pos.replace(Some(prev));
-                self.notify("current");
-                return self.song_at(current_pos - 1);
+    /// Where the playlists are kept; without a library, e.g. in the
+    /// SongListView, nothing is saved
+    pub fn set_library(&self, library: Rc<Library>) {
+        self.imp().library.replace(Some(library));
+    }
+
+    /// Run `f` with the library and the id of the active playlist,
+    /// failures are reported as PlayerError::SyncConfig
+    fn save<T, F: FnOnce(&Library, i64) -> Result<T>>(&self, f: F) -> Option<T> {
+        let library = self.imp().library.borrow().clone()?;
+        let active = self.playlist_id(self.active_playlist()).unwrap_or_default();
+        match f(&library, active) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("Failed to save the library: {}", e);
+                if let Some(tx) = &*self.imp().tx.borrow() {
+                    tx.send(PlayerAction::Error(PlayerError::SyncConfig(e)))
+                        .unwrap();
+                }
+                None
             }
         }
+    }
+
+    /// Write the whole queue into the active playlist, after a change
+    /// to it could not be saved
+    pub fn sync_config(&self) {
+        self.save(|library, active| library.replace_playlist(active, &self.to_vec()));
+    }
+
+    pub fn offline(&self) -> bool {
+        self.imp().offline.get()
+    }
+
+    /// Skip the songs which are not cached from now on, or play them again
+    pub fn set_offline(&self, offline: bool) {
+        if self.imp().offline.replace(offline) != offline {
+            self.notify("offline");
+        }
+    }
 
-        None
+    /// Whether next_song and previous_song may stop at `song`
+    fn playable(&self, song: &Song) -> bool {
+        !song.unavailable() && !song.failed() && (!self.offline() || song.uri().is_some())
     }
 
-    pub fn next_song(&self) -> Option<Song> {
-        let store = &self.imp().model;
+    pub fn previous_song(&self) -> Option<Song> {
+        let current_pos = self.imp().current_pos.get()?;
+        let prev = (0..current_pos)
+            .rev()
+            .find(|pos| match self.song_at(*pos) {
+                Some(song) => self.playable(&song),
+                None => false,
+            })?;
+        self.imp().current_pos.replace(Some(prev));
+        self.notify("current");
+        self.song_at(prev)
+    }
 
-        let n_songs = store.n_items();
+    /// The position after `current` according to `repeat_mode`, without
+    /// moving the queue
+    fn next_index(&self, current: Option<u32>, repeat_mode: RepeatMode) -> Option<u32> {
+        let n_songs = self.n_songs();
         if n_songs == 0 {
             return None;
         }
 
-        let repeat_mode = self.imp().repeat_mode.get();
-        if let Some(current) = self.current_song_index() {
-            let next: Option<u32> = match repeat_mode {
-                RepeatMode::Consecutive if current < n_songs => Some(current + 1),
+        if let Some(current) = current {
+            match repeat_mode {
+                RepeatMode::Consecutive if current + 1 < n_songs => Some(current + 1),
                 RepeatMode::RepeatOne => Some(current),
-                RepeatMode::RepeatAll if current < n_songs - 1 => Some(current + 1),
-                RepeatMode::RepeatAll if current == n_songs - 1 => Some(0),
+                RepeatMode::RepeatAll if current + 1 < n_songs => Some(current + 1),
+                RepeatMode::RepeatAll => Some(0),
                 _ => None,
+            }
+        } else {
+            // Start from the first song
+            Some(0)
+        }
+    }
+
+    /// Like next_index, but skips the songs which can not be played
+    pub fn next_playable_index(&self, current: Option<u32>) -> Option<u32> {
+        let repeat_mode = self.imp().repeat_mode.get();
+        let mut next = self.next_index(current, repeat_mode);
+        // Going round the whole queue once is enough to know nothing is left
+        for _ in 0..self.n_songs() {
+            let pos = next?;
+            match self.song_at(pos) {
+                Some(song) if self.playable(&song) => return Some(pos),
+                _ => {}
+            }
+            let repeat_mode = match repeat_mode {
+                RepeatMode::RepeatOne => RepeatMode::Consecutive,
+                mode => mode,
             };
+            next = self.next_index(Some(pos), repeat_mode);
+        }
+        None
+    }
 
-            if let Some(next) = next {
-                self.imp().current_pos.replace(Some(next));
-                self.notify("current");
-                self.song_at(next)
-            } else {
-                self.imp().current_pos.replace(None);
-                self.notify("current");
-                None
+    pub fn next_song(&self) -> Option<Song> {
+        let next = self.next_playable_index(self.current_song_index());
+        self.imp().current_pos.replace(next);
+        self.notify("current");
+        next.and_then(|pos| self.song_at(pos))
+    }
+
+    /// The songs next_song would return one after another, at most `n`,
+    /// without moving the queue
+    pub fn upcoming_songs(&self, n: u32) -> Vec<Song> {
+        let current = self.current_song_index();
+        let mut positions: Vec<u32> = Vec::new();
+        let mut pos = current;
+        while positions.len() < n as usize {
+            pos = self.next_playable_index(pos);
+            match pos {
+                // Back to where we started, with RepeatOne or RepeatAll
+                Some(p) if Some(p) == current || positions.contains(&p) => break,
+                Some(p) => positions.push(p),
+                None => break,
             }
-        } else {
-            // Return the first song
-            self.imp().current_pos.replace(Some(0));
-            self.notify("current");
-            self.song_at(0)
+        }
+        positions
+            .into_iter()
+            .filter_map(|pos| self.song_at(pos))
+            .collect()
+    }
+
+    pub fn find_song(&self, data: &SongData) -> Option<Song> {
+        (0..self.n_songs())
+            .filter_map(|pos| self.song_at(pos))
+            .find(|song| song.song_data() == *data)
+    }
+
+    /// Flag the song whose video can no longer be played, so that it is
+    /// skipped from now on
+    pub fn mark_unavailable(&self, data: &SongData) {
+        if let Some(song) = self.find_song(data) {
+            song.set_unavailable(true);
+            self.update_song(&song.song_data());
+        }
+    }
+
+    /// Flag the song which could not be played, so that it is skipped until
+    /// it is tried again
+    pub fn mark_failed(&self, data: &SongData) {
+        if let Some(song) = self.find_song(data) {
+            song.set_failed(true);
         }
     }
 
@@ -292,7 +697,8 @@ impl Queue {
             }
             self.notify("n-songs");
         }
-        self.sync_config();
+        let data: Vec<SongData> = songs.iter().map(|song| song.song_data()).collect();
+        self.save(|library, active| library.remove_songs(active, &data));
     }
 
     pub fn remove_song(&self, song: &Song) {
@@ -318,6 +724,49 @@ impl Queue {
             }
             self.notify("n-songs");
         }
-        self.sync_config();
+        self.save(|library, active| library.remove_songs(active, &[song.song_data()]));
+    }
+}
+
+/// The songs which are not `known`, each once and in their order. The
+/// history has a song once for every time it was played.
+fn new_songs(songs: &[SongData], mut known: HashSet<(String, u32)>) -> Vec<SongData> {
+    songs
+        .iter()
+        .filter(|data| known.insert((data.bvid(), data.cid())))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn song(bvid: &str, cid: u32) -> SongData {
+        let json = serde_json::json!({
+            "artist": "up",
+            "title": bvid,
+            "duration": 64,
+            "bvid": bvid,
+            "cid": cid,
+            "album": null,
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_new_songs() {
+        let batch = [
+            song("BV1", 1),
+            song("BV2", 1),
+            song("BV1", 1),
+            song("BV1", 2),
+        ];
+        let known = HashSet::from([("BV2".to_string(), 1)]);
+        let added: Vec<String> = new_songs(&batch, known)
+            .iter()
+            .map(|song| format!("{}-{}", song.bvid(), song.cid()))
+            .collect();
+        assert_eq!(added, ["BV1-1", "BV1-2"]);
     }
 }
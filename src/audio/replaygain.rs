@@ -0,0 +1,222 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use gstreamer_player::{
+    gst::{self, prelude::*},
+    prelude::Cast,
+};
+use serde::{Deserialize, Serialize};
+
+/// Loudness of a cached song as measured by `rganalysis`, in dB relative
+/// to the ReplayGain reference level
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: f64,
+    pub track_peak: f64,
+    /// Derived from the other analysed parts of the same album
+    #[serde(default)]
+    pub album_gain: Option<f64>,
+    #[serde(default)]
+    pub album_peak: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+impl Default for ReplayGainMode {
+    fn default() -> Self {
+        ReplayGainMode::Track
+    }
+}
+
+impl ReplayGainMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplayGainMode::Off => "off",
+            ReplayGainMode::Track => "track",
+            ReplayGainMode::Album => "album",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(ReplayGainMode::Off),
+            "track" => Some(ReplayGainMode::Track),
+            "album" => Some(ReplayGainMode::Album),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the whole file through `rganalysis`, this takes a moment and
+/// should not run on the main thread
+pub fn analyse(path: &Path) -> Result<ReplayGain> {
+    let pipeline = gst::parse_launch(concat!(
+        "filesrc name=src ! decodebin ! audioconvert ! audioresample ",
+        "! rganalysis ! fakesink sync=false"
+    ))?;
+    let src = pipeline
+        .downcast_ref::<gst::Bin>()
+        .and_then(|bin| bin.by_name("src"))
+        .ok_or_else(|| anyhow!("No filesrc in the analysis pipeline"))?;
+    src.set_property("location", path.to_string_lossy().to_string());
+
+    let bus = pipeline.bus().ok_or_else(|| anyhow!("No bus"))?;
+    pipeline.set_state(gst::State::Playing)?;
+
+    let mut gain = None;
+    let mut peak = None;
+    let mut result = Ok(());
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        match msg.view() {
+            gst::MessageView::Tag(tag) => {
+                let tags = tag.tags();
+                if let Some(value) = tags.get::<gst::tags::TrackGain>() {
+                    gain = Some(value.get());
+                }
+                if let Some(value) = tags.get::<gst::tags::TrackPeak>() {
+                    peak = Some(value.get());
+                }
+            }
+            gst::MessageView::Eos(..) => break,
+            gst::MessageView::Error(err) => {
+                result = Err(anyhow!("{}", err.error()));
+                break;
+            }
+            _ => {}
+        }
+    }
+    pipeline.set_state(gst::State::Null)?;
+    result?;
+
+    match (gain, peak) {
+        (Some(track_gain), Some(track_peak)) => Ok(ReplayGain {
+            track_gain,
+            track_peak,
+            album_gain: None,
+            album_peak: None,
+        }),
+        _ => Err(anyhow!("{} was not analysed", path.display())),
+    }
+}
+
+/// Album gain and peak from the analysed parts and their durations. The
+/// loudness of each part is averaged by energy, which is close to what an
+/// analysis of the parts played one after another would give.
+pub fn album_gain(tracks: &[(ReplayGain, u64)]) -> Option<(f64, f64)> {
+    if tracks.is_empty() {
+        return None;
+    }
+    let total: u64 = tracks.iter().map(|(_, duration)| (*duration).max(1)).sum();
+    let energy = tracks
+        .iter()
+        .map(|(rg, duration)| {
+            // A lower gain means a louder track
+            10f64.powf(-rg.track_gain / 10.0) * (*duration).max(1) as f64
+        })
+        .sum::<f64>()
+        / total as f64;
+    let gain = -10.0 * energy.log10();
+    let peak = tracks
+        .iter()
+        .map(|(rg, _)| rg.track_peak)
+        .fold(0.0, f64::max);
+    Some((gain, peak))
+}
+
+/// dB the pre-amp can go up or down
+pub const MAX_PRE_AMP: f64 = 15.0;
+
+/// The highest volume the GStreamer `volume` element accepts
+const MAX_VOLUME: f64 = 10.0;
+
+/// Linear volume for a song, never so loud that its peak would clip
+pub fn gain_factor(rg: Option<&ReplayGain>, mode: ReplayGainMode, pre_amp: f64) -> f64 {
+    let rg = match rg {
+        Some(rg) => rg,
+        None => return 1.0,
+    };
+    let (gain, peak) = match mode {
+        ReplayGainMode::Off => return 1.0,
+        ReplayGainMode::Track => (rg.track_gain, rg.track_peak),
+        ReplayGainMode::Album => (
+            rg.album_gain.unwrap_or(rg.track_gain),
+            rg.album_peak.unwrap_or(rg.track_peak),
+        ),
+    };
+    let factor = 10f64.powf((gain + pre_amp) / 20.0);
+    let factor = if peak > 0.0 {
+        factor.min(1.0 / peak)
+    } else {
+        factor
+    };
+    factor.clamp(0.0, MAX_VOLUME)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(track_gain: f64, track_peak: f64) -> ReplayGain {
+        ReplayGain {
+            track_gain,
+            track_peak,
+            album_gain: None,
+            album_peak: None,
+        }
+    }
+
+    #[test]
+    fn test_album_gain() {
+        assert_eq!(album_gain(&[]), None);
+
+        let (gain, peak) = album_gain(&[(track(-3.0, 0.5), 100)]).unwrap();
+        assert!((gain + 3.0).abs() < 1e-9);
+        assert_eq!(peak, 0.5);
+
+        // The louder part dominates
+        let (gain, peak) = album_gain(&[(track(-6.0, 0.9), 100), (track(0.0, 0.4), 100)]).unwrap();
+        assert!(gain < -3.0 && gain > -6.0);
+        assert_eq!(peak, 0.9);
+    }
+
+    #[test]
+    fn test_gain_factor() {
+        let rg = ReplayGain {
+            album_gain: Some(-6.0),
+            album_peak: Some(0.5),
+            ..track(6.0, 0.8)
+        };
+        assert_eq!(gain_factor(None, ReplayGainMode::Track, 0.0), 1.0);
+        assert_eq!(gain_factor(Some(&rg), ReplayGainMode::Off, 0.0), 1.0);
+        // +6 dB would push the peak past full scale
+        assert_eq!(
+            gain_factor(Some(&rg), ReplayGainMode::Track, 0.0),
+            1.0 / 0.8
+        );
+        let album = gain_factor(Some(&rg), ReplayGainMode::Album, 0.0);
+        assert!((album - 10f64.powf(-6.0 / 20.0)).abs() < 1e-9);
+        let pre_amp = gain_factor(Some(&rg), ReplayGainMode::Album, 6.0);
+        assert!((pre_amp - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_gain_factor_clamped() {
+        // A very quiet track without a known peak
+        let quiet = track(30.0, 0.0);
+        assert_eq!(
+            gain_factor(Some(&quiet), ReplayGainMode::Track, 6.0),
+            MAX_VOLUME
+        );
+        let quiet = track(30.0, 0.01);
+        assert_eq!(
+            gain_factor(Some(&quiet), ReplayGainMode::Track, MAX_PRE_AMP),
+            MAX_VOLUME
+        );
+    }
+}
@@ -114,6 +114,19 @@ impl ShuffleListModel {
         self.imp().shuffle.borrow().is_some()
     }
 
+    /// The positions of the underlying model in shuffled order
+    pub fn order(&self) -> Option<Vec<u32>> {
+        self.imp().shuffle.borrow().clone()
+    }
+
+    /// Shuffle into a known order, e.g. one saved before a restart
+    pub fn set_order(&self, order: Vec<u32>) {
+        if let Some(ref model) = *self.imp().model.borrow() {
+            self.imp().shuffle.replace(Some(order));
+            self.items_changed(0, model.n_items(), model.n_items());
+        }
+    }
+
     pub fn unshuffle(&self) {
         if let Some(ref model) = *self.imp().model.borrow() {
             self.imp().shuffle.replace(None);
@@ -122,10 +135,20 @@ impl ShuffleListModel {
     }
 
     pub fn reshuffle(&self) {
+        self.reshuffle_with_first(None);
+    }
+
+    /// Shuffle, but keep the item at `first` of the underlying model in
+    /// front, e.g. the song which is playing
+    pub fn reshuffle_with_first(&self, first: Option<u32>) {
         if let Some(ref model) = *self.imp().model.borrow() {
             let mut positions: Vec<u32> = (0..model.n_items()).collect();
             let mut rng = thread_rng();
             positions.shuffle(&mut rng);
+            let first = first.and_then(|first| positions.iter().position(|p| *p == first));
+            if let Some(idx) = first {
+                positions.swap(0, idx);
+            }
 
             self.imp().shuffle.replace(Some(positions));
             self.items_changed(0, model.n_items(), model.n_items());
@@ -1,14 +1,44 @@
-use anyhow::Result;
+use std::path::{Path, PathBuf};
+
 use gtk::{glib, prelude::*, subclass::prelude::*};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 
 
 use crate::{
-    bilibili::{data::BvidInfo, download_song, get_url},
+    bilibili::{
+        data::{AudioStream, BvidInfo},
+        download_song, get_favorite_medias, get_url, resolve_short_link, BiliInput, Error, Result,
+    },
     config::CACHE_DIR,
+    settings,
+};
+
+use super::{
+    cache,
+    replaygain::ReplayGain,
+    tags::{self, Source},
 };
 
-#[derive(Deserialize, Serialize, Clone)]
+/// The DASH stream a song has been cached from
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct StreamInfo {
+    pub quality: u32,
+    pub codec: String,
+    pub extension: String,
+}
+
+impl From<&AudioStream> for StreamInfo {
+    fn from(stream: &AudioStream) -> Self {
+        StreamInfo {
+            quality: stream.id,
+            codec: stream.codecs.clone(),
+            extension: stream.extension().to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct SongData {
     artist: Option<String>,
     title: String,
@@ -16,6 +46,21 @@ pub struct SongData {
     bvid: String,
     cid: u32,
     album: Option<String>,
+    /// The video has been deleted, hidden or region locked
+    #[serde(default)]
+    unavailable: bool,
+    /// Quality and codec of the cached file, None until it is downloaded
+    #[serde(default)]
+    stream: Option<StreamInfo>,
+    /// Loudness of the cached file, None until it is analysed
+    #[serde(default)]
+    replay_gain: Option<ReplayGain>,
+    /// Video cover, or the avatar of the uploader
+    #[serde(default)]
+    cover_url: Option<String>,
+    /// Part of a multi-part video, starting from 1
+    #[serde(default)]
+    page: Option<u32>,
 }
 
 impl Default for SongData {
@@ -27,6 +72,11 @@ impl Default for SongData {
             bvid: "Invalid bvid".to_string(),
             cid: 0,
             album: Some("Invalid Album".to_string()),
+            unavailable: false,
+            stream: None,
+            replay_gain: None,
+            cover_url: None,
+            page: None,
         }
     }
 }
@@ -44,23 +94,25 @@ impl SongData {
         self.title.as_str()
     }
 
-    fn escape(s: &str) -> String {
-        s.replace("/", ",")
+    /// Container of the cached file, m4a until the song is downloaded
+    pub fn extension(&self) -> &str {
+        self.stream.as_ref().map_or("m4a", |s| s.extension.as_str())
+    }
+
+    /// Names the cached file after the part of the video and its quality,
+    /// so that songs sharing a title never share a file
+    pub fn cache_key(&self) -> String {
+        let quality = self.stream.as_ref().map_or(0, |s| s.quality);
+        format!("{}-{}-{}", self.bvid, self.cid, quality)
     }
 
     pub fn file_name(&self) -> String {
-        let suffix: &str = ".m4a";
-        if let Some(s) = self.album() {
-            let mut s = Self::escape(s);
-            s.push('-');
-            s.push_str(&Self::escape(self.title()));
-            s.push_str(suffix);
-            s
-        } else {
-            let mut s = Self::escape(self.title());
-            s.push_str(suffix);
-            s
-        }
+        format!("{}.{}", self.cache_key(), self.extension())
+    }
+
+    /// Where the song is, or will be, cached
+    pub fn cache_path(&self) -> PathBuf {
+        CACHE_DIR.join(self.file_name())
     }
 
     pub fn duration(&self) -> u64 {
@@ -71,6 +123,38 @@ impl SongData {
         String::clone(&self.bvid)
     }
 
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    pub fn page(&self) -> Option<u32> {
+        self.page
+    }
+
+    /// A song found in the cache, as described by the tags of its file
+    pub(super) fn from_tags(
+        source: Source,
+        title: String,
+        artist: Option<String>,
+        album: Option<String>,
+        duration: u64,
+        stream: StreamInfo,
+    ) -> Self {
+        SongData {
+            artist,
+            title,
+            duration,
+            bvid: source.bvid,
+            cid: source.cid,
+            album,
+            unavailable: false,
+            stream: Some(stream),
+            replay_gain: None,
+            cover_url: None,
+            page: source.page,
+        }
+    }
+
     pub fn from_bvid(bvid: &str) -> Result<Vec<SongData>> {
         let mut songs = Vec::new();
         let bvid_info: BvidInfo = BvidInfo::from_bvid(bvid)?;
@@ -86,6 +170,11 @@ impl SongData {
                         duration: i.page.duration,
                         bvid: i.bvid,
                         cid: i.page.cid,
+                        unavailable: false,
+                        stream: None,
+                        replay_gain: None,
+                        cover_url: bvid_info.get_cover(),
+                        page: None,
                     };
                     songs.push(song_data);
                 }
@@ -98,6 +187,11 @@ impl SongData {
                     duration: page.duration,
                     bvid: bvid.to_string(),
                     cid: page.cid,
+                    unavailable: false,
+                    stream: None,
+                    replay_gain: None,
+                    cover_url: bvid_info.get_cover(),
+                    page: None,
                 };
                 songs.push(song_data);
             }
@@ -110,6 +204,11 @@ impl SongData {
                     duration: i.duration,
                     bvid: bvid.to_string(),
                     cid: i.cid,
+                    unavailable: false,
+                    stream: None,
+                    replay_gain: None,
+                    cover_url: bvid_info.get_cover(),
+                    page: Some(i.page),
                 };
                 songs.push(song_data);
             }
@@ -118,15 +217,193 @@ impl SongData {
         Ok(songs)
     }
 
-    pub fn download(&self) -> Result<String> {
-        let song_path = CACHE_DIR.join(self.file_name());
-        let url = get_url(self.bvid.as_str(), self.cid)?;
-        download_song(url.as_str(), song_path.to_str().unwrap())?;
+    /// Only the page selected with `?p=`, starting from 1
+    pub fn from_bvid_page(bvid: &str, page: u32) -> Result<Vec<SongData>> {
+        let bvid_info: BvidInfo = BvidInfo::from_bvid(bvid)?;
+        let pages = bvid_info.get_pages();
+        let i = page
+            .checked_sub(1)
+            .and_then(|idx| pages.get(idx as usize))
+            .ok_or_else(|| Error::Invalid(format!("{} 没有第 {} P", bvid, page)))?;
+
+        let (title, album, page) = if pages.len() == 1 {
+            (bvid_info.get_titile().clone(), None, None)
+        } else {
+            (
+                i.part.clone(),
+                Some(bvid_info.get_titile().clone()),
+                Some(page),
+            )
+        };
+        Ok(vec![Self {
+            artist: Some(bvid_info.get_author().clone()),
+            title,
+            album,
+            duration: i.duration,
+            bvid: bvid.to_string(),
+            cid: i.cid,
+            unavailable: false,
+            stream: None,
+            replay_gain: None,
+            cover_url: bvid_info.get_cover(),
+            page,
+        }])
+    }
+
+    /// Songs for one entry of the input popover, along with the entries
+    /// that could not be added
+    pub fn from_input(input: BiliInput) -> Result<(Vec<SongData>, Vec<String>)> {
+        match input {
+            BiliInput::Video(bvid, None) => Ok((Self::from_bvid(&bvid)?, Vec::new())),
+            BiliInput::Video(bvid, Some(page)) => {
+                Ok((Self::from_bvid_page(&bvid, page)?, Vec::new()))
+            }
+            BiliInput::ShortLink(url) => Self::from_input(resolve_short_link(&url)?),
+            BiliInput::Favorites(media_id) => Self::from_favorites(media_id),
+        }
+    }
+
+    /// Every playable song of a favorites folder, along with a description
+    /// of each entry that could not be turned into a song
+    pub fn from_favorites(media_id: u64) -> Result<(Vec<SongData>, Vec<String>)> {
+        let mut songs = Vec::new();
+        let mut invalid = Vec::new();
+
+        for media in get_favorite_medias(media_id)? {
+            if !media.is_valid() {
+                invalid.push(format!("{} ({})", media.title, media.bvid));
+                continue;
+            }
+
+            match (media.page, &media.ugc) {
+                (1, Some(ugc)) => songs.push(Self {
+                    cover_url: media.cover(),
+                    page: None,
+                    artist: media.upper.map(|upper| upper.name),
+                    title: media.title,
+                    album: None,
+                    duration: media.duration,
+                    bvid: media.bvid,
+                    cid: ugc.first_cid,
+                    unavailable: false,
+                    stream: None,
+                    replay_gain: None,
+                }),
+                _ => match Self::from_bvid(&media.bvid) {
+                    Ok(mut data) => songs.append(&mut data),
+                    Err(e) => invalid.push(format!("{} ({}): {}", media.title, media.bvid, e)),
+                },
+            }
+        }
+
+        Ok((songs, invalid))
+    }
+
+    pub fn stream(&self) -> Option<&StreamInfo> {
+        self.stream.as_ref()
+    }
+
+    pub fn replay_gain(&self) -> Option<&ReplayGain> {
+        self.replay_gain.as_ref()
+    }
+
+    pub fn cover_url(&self) -> Option<&str> {
+        self.cover_url.as_deref()
+    }
+
+    /// The cover is shared by the pages of a video
+    pub fn cover_file_name(&self) -> String {
+        format!("{}{}", self.bvid, cache::COVER_SUFFIX)
+    }
+
+    /// Where the cover is cached, next to the audio
+    pub fn cover_path(&self) -> PathBuf {
+        CACHE_DIR.join(self.cover_file_name())
+    }
+
+    /// Save the cover into the cache. Songs added before covers were
+    /// known look their url up first.
+    pub fn download_cover(&mut self) -> Result<PathBuf> {
+        let path = self.cover_path();
+        if path.exists() {
+            return Ok(path);
+        }
+        if self.cover_url.is_none() {
+            self.cover_url = BvidInfo::from_bvid(&self.bvid)?.get_cover();
+        }
+        let url = self
+            .cover_url
+            .as_ref()
+            .ok_or_else(|| Error::Invalid(format!("{} 没有封面", self.bvid)))?;
+        download_song(url, &path, |_, _| true, |_| {})?;
+        Ok(path)
+    }
+
+    /// The audio stream matching the preferred quality
+    pub fn audio_stream(&self) -> Result<AudioStream> {
+        get_url(self.bvid.as_str(), self.cid, settings::read(|s| s.quality))
+    }
+
+    pub fn download<F: FnMut(u64, Option<u64>) -> bool>(&mut self, progress: F) -> Result<String> {
+        let stream = self.audio_stream()?;
+        self.download_from(&stream, progress)
+    }
+
+    /// Save an already resolved stream into the cache, and remember its
+    /// quality so the file name gets the right extension
+    pub fn download_from<F: FnMut(u64, Option<u64>) -> bool>(
+        &mut self,
+        stream: &AudioStream,
+        progress: F,
+    ) -> Result<String> {
+        self.stream = Some(StreamInfo::from(stream));
+        let song_path = self.cache_path();
+        // The cover comes alongside the audio, the file is tagged without
+        // it rather than waiting
+        let mut data = self.clone();
+        let cover = std::thread::spawn(move || -> Result<(PathBuf, Option<String>)> {
+            let path = data.download_cover()?;
+            Ok((path, data.cover_url))
+        });
+        let mut cover_url = None;
+        download_song(&stream.base_url, &song_path, progress, |part| {
+            let cover = if cover.is_finished() {
+                match cover.join() {
+                    Ok(Ok((path, url))) => {
+                        cover_url = url;
+                        Some(path)
+                    }
+                    Ok(Err(e)) => {
+                        warn!("Unable to fetch the cover of {}: {}", self.title(), e);
+                        None
+                    }
+                    Err(_) => None,
+                }
+            } else {
+                debug!("The cover of {} is not there yet", self.title());
+                None
+            };
+            self.tag_file(part, cover.as_deref());
+        })?;
+        if cover_url.is_some() {
+            self.cover_url = cover_url;
+        }
+        cache::index(self);
         let uri = format!("file://{}", song_path.display());
         Ok(uri)
     }
 }
 
+impl SongData {
+    /// Embed the details and the cover into the downloaded file, a file
+    /// without them still plays
+    fn tag_file(&self, path: &Path, cover: Option<&Path>) {
+        if let Err(e) = tags::write_tags(path, self, cover) {
+            warn!("Unable to tag {}: {}", path.display(), e);
+        }
+    }
+}
+
 impl PartialEq for SongData {
     fn eq(&self, other: &Self) -> bool {
         return (self.bvid == other.bvid) && (self.cid == other.cid);
@@ -137,7 +414,9 @@ mod imp {
     use std::cell::{Cell, RefCell};
 
     use gstreamer::glib::once_cell::sync::Lazy;
-    use gtk::glib::{ParamFlags, ParamSpec, ParamSpecBoolean, ParamSpecString, ParamSpecUInt};
+    use gtk::glib::{
+        ParamFlags, ParamSpec, ParamSpecBoolean, ParamSpecDouble, ParamSpecString, ParamSpecUInt,
+    };
 
     use super::*;
 
@@ -146,6 +425,9 @@ mod imp {
         pub data: RefCell<SongData>,
         pub playing: Cell<bool>,
         pub selected: Cell<bool>,
+        pub download_progress: Cell<f64>,
+        /// The last attempt to play the song failed, not kept across runs
+        pub failed: Cell<bool>,
     }
 
     #[glib::object_subclass]
@@ -171,6 +453,19 @@ mod imp {
                     ParamSpecString::new("title", "", "", None, ParamFlags::READABLE),
                     ParamSpecBoolean::new("playing", "", "", false, ParamFlags::READWRITE),
                     ParamSpecBoolean::new("selected", "", "", false, ParamFlags::READWRITE),
+                    ParamSpecBoolean::new("unavailable", "", "", false, ParamFlags::READABLE),
+                    ParamSpecBoolean::new("failed", "", "", false, ParamFlags::READABLE),
+                    ParamSpecBoolean::new("cached", "", "", false, ParamFlags::READABLE),
+                    ParamSpecString::new("cover", "", "", None, ParamFlags::READABLE),
+                    ParamSpecDouble::new(
+                        "download-progress",
+                        "",
+                        "",
+                        0.0,
+                        1.0,
+                        0.0,
+                        ParamFlags::READABLE,
+                    ),
                 ]
             });
             PROPERTIES.as_ref()
@@ -216,6 +511,11 @@ mod imp {
                 "bvid" => obj.bvid().to_value(),
                 "playing" => self.playing.get().to_value(),
                 "selected" => self.selected.get().to_value(),
+                "unavailable" => obj.unavailable().to_value(),
+                "failed" => obj.failed().to_value(),
+                "cached" => obj.uri().is_some().to_value(),
+                "cover" => obj.cover().to_value(),
+                "download-progress" => obj.download_progress().to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -299,8 +599,13 @@ impl Song {
         self.imp().data.borrow().cid
     }
 
+    /// Where the song is, or will be, cached
+    pub fn cache_path(&self) -> PathBuf {
+        self.imp().data.borrow().cache_path()
+    }
+
     pub fn uri(&self) -> Option<String> {
-        let song_path = CACHE_DIR.join(self.file_name());
+        let song_path = self.cache_path();
         if song_path.exists() {
             let s = glib::filename_to_uri(song_path, None).unwrap().into();
             Some(s)
@@ -309,10 +614,41 @@ impl Song {
         }
     }
 
+    /// Path of the cached cover, None until it is downloaded
+    pub fn cover(&self) -> Option<String> {
+        let path = self.imp().data.borrow().cover_path();
+        path.exists().then(|| path.to_string_lossy().into_owned())
+    }
+
+    /// The cover has been downloaded from `cover_url`
+    pub fn set_cover_url(&self, cover_url: Option<String>) {
+        self.imp().data.borrow_mut().cover_url = cover_url;
+        self.notify("cover");
+    }
+
     pub fn song_data(&self) -> SongData {
         self.imp().data.borrow().clone()
     }
 
+    /// Between 0 and 1 while the song is being downloaded
+    pub fn download_progress(&self) -> f64 {
+        self.imp().download_progress.get()
+    }
+
+    pub fn set_download_progress(&self, progress: f64) {
+        if self.imp().download_progress.replace(progress) != progress {
+            self.notify("download-progress");
+        }
+    }
+
+    pub fn set_stream(&self, stream: Option<StreamInfo>) {
+        self.imp().data.borrow_mut().stream = stream;
+    }
+
+    pub fn set_replay_gain(&self, replay_gain: Option<ReplayGain>) {
+        self.imp().data.borrow_mut().replay_gain = replay_gain;
+    }
+
     pub fn equals(&self, other: &Self) -> bool {
         *self.imp().data.borrow() == *other.imp().data.borrow()
     }
@@ -320,6 +656,28 @@ impl Song {
     pub fn playing(&self) -> bool {
         self.imp().playing.get()
     }
+
+    pub fn unavailable(&self) -> bool {
+        self.imp().data.borrow().unavailable
+    }
+
+    pub fn set_unavailable(&self, unavailable: bool) {
+        let was_unavailable =
+            std::mem::replace(&mut self.imp().data.borrow_mut().unavailable, unavailable);
+        if was_unavailable != unavailable {
+            self.notify("unavailable");
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.imp().failed.get()
+    }
+
+    pub fn set_failed(&self, failed: bool) {
+        if self.imp().failed.replace(failed) != failed {
+            self.notify("failed");
+        }
+    }
 }
 
 #[cfg(test)]
@@ -48,6 +48,7 @@ mod imp {
                     ParamSpecString::new("title", "", "", None, ParamFlags::READABLE),
                     ParamSpecString::new("artist", "", "", None, ParamFlags::READABLE),
                     ParamSpecString::new("album", "", "", None, ParamFlags::READABLE),
+                    ParamSpecString::new("cover", "", "", None, ParamFlags::READABLE),
                     ParamSpecUInt64::new("duration", "", "", 0, u64::MAX, 0, ParamFlags::READABLE),
                     ParamSpecDouble::new("volume", "", "", 0.0, 1.0, 1.0, ParamFlags::READABLE),
                 ]
@@ -66,6 +67,7 @@ mod imp {
                 "title" => obj.title().to_value(),
                 "artist" => obj.artist().to_value(),
                 "album" => obj.album().to_value(),
+                "cover" => obj.cover().to_value(),
                 "duration" => obj.duration().to_value(),
                 _ => unimplemented!(),
             }
@@ -106,6 +108,15 @@ impl PlayerState {
         None
     }
 
+    /// Path of the cached cover of the current song
+    pub fn cover(&self) -> Option<String> {
+        self.imp()
+            .current_song
+            .borrow()
+            .as_ref()
+            .and_then(|song| song.cover())
+    }
+
     pub fn duration(&self) -> u64 {
         if let Some(song) = &*self.imp().current_song.borrow() {
             return song.duration();
@@ -119,6 +130,10 @@ impl PlayerState {
         matches!(playback_state, PlaybackState::Playing)
     }
 
+    pub fn playback_state(&self) -> PlaybackState {
+        self.imp().playback_state.get()
+    }
+
     pub fn set_playback_state(&self, playback_state: &PlaybackState) -> bool {
         let old_state = self.imp().playback_state.replace(*playback_state);
         if old_state != *playback_state {
@@ -140,6 +155,7 @@ impl PlayerState {
         self.notify("title");
         self.notify("artist");
         self.notify("album");
+        self.notify("cover");
         self.notify("duration");
         self.notify("position");
     }
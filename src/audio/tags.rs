@@ -0,0 +1,175 @@
+use std::{fs, path::Path};
+
+use lofty::{
+    Accessor, ItemKey, MimeType, Picture, PictureType, Probe, Tag, TagExt, TaggedFile,
+    TaggedFileExt,
+};
+use log::{debug, warn};
+
+use super::{cache, song::StreamInfo, SongData};
+
+const VIDEO_URL: &str = "https://www.bilibili.com/video/";
+
+/// Where a cached file came from, kept in the comment of its tags as
+/// `https://www.bilibili.com/video/BV1xx411c7mD?p=2 cid=1176840`
+#[derive(Debug, PartialEq)]
+pub struct Source {
+    pub bvid: String,
+    pub cid: u32,
+    /// Part of a multi-part video, starting from 1
+    pub page: Option<u32>,
+}
+
+impl Source {
+    pub fn url(&self) -> String {
+        match self.page {
+            Some(page) => format!("{}{}?p={}", VIDEO_URL, self.bvid, page),
+            None => format!("{}{}", VIDEO_URL, self.bvid),
+        }
+    }
+
+    fn to_comment(&self) -> String {
+        format!("{} cid={}", self.url(), self.cid)
+    }
+
+    fn from_comment(comment: &str) -> Option<Self> {
+        let (url, cid) = comment.trim().rsplit_once(" cid=")?;
+        let video = url.strip_prefix(VIDEO_URL)?;
+        let (bvid, page) = match video.split_once("?p=") {
+            Some((bvid, page)) => (bvid, Some(page.parse().ok()?)),
+            None => (video, None),
+        };
+        if bvid.is_empty() {
+            return None;
+        }
+        Some(Source {
+            bvid: bvid.to_string(),
+            cid: cid.parse().ok()?,
+            page,
+        })
+    }
+}
+
+/// Write the details of `data` into the cached file at `path`, along with
+/// the cover at `cover` if there is one, so that the file still says what
+/// it is when it is copied out of the cache
+pub fn write_tags(path: &Path, data: &SongData, cover: Option<&Path>) -> lofty::Result<()> {
+    // The content tells the format, downloads are tagged as `.part` files
+    let mut tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
+    let tag = primary_tag(&mut tagged_file);
+
+    tag.set_title(data.title().to_string());
+    if let Some(artist) = data.artist() {
+        tag.set_artist(artist.to_string());
+    }
+    if let Some(album) = data.album() {
+        tag.set_album(album.to_string());
+    }
+    if let Some(page) = data.page() {
+        tag.set_track(page);
+    }
+    let source = Source {
+        bvid: data.bvid(),
+        cid: data.cid(),
+        page: data.page(),
+    };
+    tag.insert_text(ItemKey::AudioSourceUrl, source.url());
+    tag.insert_text(ItemKey::Comment, source.to_comment());
+
+    if let Some(cover) = cover {
+        match fs::read(cover) {
+            Ok(image) => {
+                tag.remove_picture_type(PictureType::CoverFront);
+                tag.push_picture(Picture::new_unchecked(
+                    PictureType::CoverFront,
+                    MimeType::Jpeg,
+                    None,
+                    image,
+                ));
+            }
+            Err(e) => warn!("Unable to read {}: {}", cover.display(), e),
+        }
+    }
+
+    tag.save_to_path(path)
+}
+
+fn primary_tag(tagged_file: &mut TaggedFile) -> &mut Tag {
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    tagged_file.primary_tag_mut().unwrap()
+}
+
+/// The song a cached file holds, None unless it was tagged by write_tags
+pub fn read_tags(path: &Path) -> Option<SongData> {
+    let tagged_file = lofty::read_from_path(path).ok()?;
+    let tag = tagged_file.primary_tag()?;
+    let source = Source::from_comment(tag.get_string(&ItemKey::Comment)?)?;
+    // Only the name of the file tells the quality, files cached under
+    // their title have an unknown one
+    let stream = StreamInfo {
+        quality: cache::key_quality(path, &source.bvid, source.cid).unwrap_or(0),
+        codec: String::new(),
+        extension: path.extension()?.to_string_lossy().into_owned(),
+    };
+
+    Some(SongData::from_tags(
+        source,
+        tag.title()?.into_owned(),
+        tag.artist().map(|artist| artist.into_owned()),
+        tag.album().map(|album| album.into_owned()),
+        tagged_file.properties().duration().as_secs(),
+        stream,
+    ))
+}
+
+/// Every tagged song in `dir`, for rebuilding the library from the cache
+pub fn scan_cache(dir: &Path) -> Vec<SongData> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Unable to read {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    let mut songs = Vec::new();
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        if let Some(data) = read_tags(&path) {
+            debug!("Found {} in {}", data.title(), path.display());
+            songs.push(data);
+        }
+    }
+    songs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_source_comment() {
+        let source = Source {
+            bvid: "BV1xx411c7mD".to_string(),
+            cid: 1176840,
+            page: Some(2),
+        };
+        let comment = source.to_comment();
+        assert_eq!(
+            comment,
+            "https://www.bilibili.com/video/BV1xx411c7mD?p=2 cid=1176840"
+        );
+        assert_eq!(Source::from_comment(&comment), Some(source));
+
+        let single = Source::from_comment("https://www.bilibili.com/video/BV1 cid=1").unwrap();
+        assert_eq!(single.bvid, "BV1");
+        assert_eq!(single.page, None);
+
+        assert_eq!(Source::from_comment("encoded by someone"), None);
+        assert_eq!(
+            Source::from_comment("https://www.bilibili.com/video/ cid=1"),
+            None
+        );
+    }
+}
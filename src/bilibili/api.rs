@@ -1,57 +1,266 @@
+use lazy_static::lazy_static;
 use log::debug;
-use std::fs::File;
-use std::io::Write;
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
 
-use anyhow::{Ok, Result};
+use super::data::{
+    ApiResponse, AudioStream, BvidInfo, FavoriteList, FavoriteMedia, PlayUrl, QualityPreference,
+    SearchOrder, SearchResult,
+};
+use super::error::{Error, Result};
 
-use crate::{audio::Song, config::CACHE_DIR};
+pub static BILIBILI_UA: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/98.0.4758.102 Safari/537.36 Edg/98.0.1108.56";
+pub static BILIBILI_REFERER: &str = "https://www.bilibili.com/";
 
-use super::data::{BvidInfo, PlayUrl};
+/// Whether the requests are refused before they are sent
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Turn offline mode on or off for every request below
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+/// Fail right away instead of waiting for the network to time out
+pub(super) fn check_online() -> Result<()> {
+    if OFFLINE.load(Ordering::Relaxed) {
+        return Err(Error::Offline);
+    }
+    Ok(())
+}
+
+/// Every endpoint answers 200 and reports failures in `code`
+fn parse_response<T: DeserializeOwned>(resp: &str) -> Result<T> {
+    let resp: ApiResponse = serde_json::from_str(resp)?;
+    resp.into_data()
+}
 
 impl BvidInfo {
     pub fn from_bvid(bvid: &str) -> Result<BvidInfo> {
         const URL_BVID_INFO: &str = "http://api.bilibili.com/x/web-interface/view?bvid=";
+        check_online()?;
         let req = format!("{}{}", URL_BVID_INFO, bvid).to_string();
         let resp = ureq::get(&req).call()?.into_string()?;
-        let info: BvidInfo = serde_json::from_str(resp.as_str())?;
+        let info: BvidInfo = parse_response(resp.as_str())?;
         Ok(info)
     }
 }
 
-pub fn get_url(bvid: &str, cid: u32) -> Result<String> {
+impl FavoriteList {
+    pub fn from_media_id(media_id: u64, page: u32) -> Result<FavoriteList> {
+        const URL_FAV_LIST: &str = "https://api.bilibili.com/x/v3/fav/resource/list";
+        check_online()?;
+        let resp = ureq::get(URL_FAV_LIST)
+            .set("User-Agent", BILIBILI_UA)
+            .set("Referer", BILIBILI_REFERER)
+            .query("media_id", &media_id.to_string())
+            .query("pn", &page.to_string())
+            .query("ps", "20")
+            .query("platform", "web")
+            .call()?
+            .into_string()?;
+        let list: FavoriteList = parse_response(resp.as_str())?;
+        Ok(list)
+    }
+}
+
+/// Fetch every entry of a favorites folder, following the pages
+pub fn get_favorite_medias(media_id: u64) -> Result<Vec<FavoriteMedia>> {
+    let mut medias = Vec::new();
+    let mut page = 1;
+    loop {
+        let list = FavoriteList::from_media_id(media_id, page)?;
+        debug!(
+            "favorites: {}, page {}, {} medias",
+            list.title(),
+            page,
+            list.media_count()
+        );
+        let has_more = list.has_more();
+        medias.append(&mut list.into_medias());
+        if !has_more {
+            break;
+        }
+        page += 1;
+    }
+    Ok(medias)
+}
+
+/// Pick one of the audio streams of a page. `fnval=4048` asks for every
+/// DASH format, which includes the dolby and flac audio.
+pub fn get_url(bvid: &str, cid: u32, preference: QualityPreference) -> Result<AudioStream> {
+    check_online()?;
     let req = format!(
-        "https://api.bilibili.com/x/player/playurl?cid={}&bvid={}&qn=64&fnval=16",
+        "https://api.bilibili.com/x/player/playurl?cid={}&bvid={}&fnval=4048&fourk=1",
         cid, bvid
     )
     .to_string();
     let resp = ureq::get(&req).call()?.into_string()?;
-    let play_url: PlayUrl = serde_json::from_str(resp.as_str())?;
-    let url = play_url.data.dash.audio[0].baseUrl.clone();
-    debug!("bvid: {}, cid: {}, url: {}", bvid, cid, url);
-    Ok(url)
+    let play_url: PlayUrl = parse_response(resp.as_str())?;
+    let stream = preference
+        .select(play_url.dash.into_streams())
+        .ok_or_else(|| Error::Invalid(format!("{} 没有音频", bvid)))?;
+    debug!(
+        "bvid: {}, cid: {}, quality: {}, codecs: {}, url: {}",
+        bvid, cid, stream.id, stream.codecs, stream.base_url
+    );
+    Ok(stream)
 }
 
-pub fn download_song(url: &str, path: &str) -> Result<()> {
-    static BILIBILI_UA: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/98.0.4758.102 Safari/537.36 Edg/98.0.1108.56";
-    static BILIBILI_REFERER: &str = "https://www.bilibili.com/";
-
-    let resp = ureq::get(url)
+pub fn search_video(keyword: &str, page: u32, order: SearchOrder) -> Result<SearchResult> {
+    const URL_SEARCH: &str = "https://api.bilibili.com/x/web-interface/search/type";
+    check_online()?;
+    let resp = ureq::get(URL_SEARCH)
         .set("User-Agent", BILIBILI_UA)
         .set("Referer", BILIBILI_REFERER)
-        .call()?;
+        .query("search_type", "video")
+        .query("keyword", keyword)
+        .query("page", &page.to_string())
+        .query("order", order.as_str())
+        .call()?
+        .into_string()?;
+    let result: SearchResult = parse_response(resp.as_str())?;
+    debug!(
+        "search: {}, page {}/{}",
+        keyword,
+        result.page(),
+        result.num_pages()
+    );
+    Ok(result)
+}
 
-    let mut dest = File::create(path)?;
-    let mut bytes: Vec<u8> = Vec::new();
-    resp.into_reader().read_to_end(&mut bytes)?;
-    dest.write(bytes.as_slice())?;
-    Ok(())
+lazy_static! {
+    /// Files being downloaded right now, so that two threads never append
+    /// to the same part file
+    static ref DOWNLOADING: (Mutex<HashSet<PathBuf>>, Condvar) = Default::default();
+}
+
+/// Held while `path` is being downloaded
+struct DownloadGuard(PathBuf);
+
+impl DownloadGuard {
+    /// Wait until no other thread is downloading `path`
+    fn acquire(path: &Path) -> Self {
+        let (lock, cvar) = &*DOWNLOADING;
+        let mut downloading = lock.lock().unwrap();
+        while downloading.contains(path) {
+            downloading = cvar.wait(downloading).unwrap();
+        }
+        downloading.insert(path.to_path_buf());
+        DownloadGuard(path.to_path_buf())
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*DOWNLOADING;
+        lock.lock().unwrap().remove(&self.0);
+        cvar.notify_all();
+    }
+}
+
+/// `bytes 100-199/1000` -> 1000
+fn content_range_total(value: &str) -> Option<u64> {
+    value.rsplit_once('/')?.1.trim().parse().ok()
 }
 
-pub fn remove_cache(songs: &Vec<Song>) {
-    for i in songs {
-        let path = CACHE_DIR.join(i.file_name());
-        if std::fs::remove_file(path).is_ok() {
-            debug!("Clear cache: {}", i.file_name());
+/// Download `url` into `path`. The data goes to `path.part` first, which is
+/// resumed with a Range request if an earlier download was interrupted, and
+/// only renamed to `path` once its size matches what the server announced.
+/// `progress` is called with the bytes received so far and the total size,
+/// returning false cancels the download and keeps the part file.
+/// `finish` gets the complete part file right before it is renamed.
+pub fn download_song<F, G>(url: &str, path: &Path, progress: F, finish: G) -> Result<()>
+where
+    F: FnMut(u64, Option<u64>) -> bool,
+    G: FnOnce(&Path),
+{
+    check_online()?;
+    let _guard = DownloadGuard::acquire(path);
+    // Another thread may have finished it while we were waiting
+    if path.exists() {
+        return Ok(());
+    }
+    download_part(url, path, progress, finish)
+}
+
+fn download_part<F, G>(url: &str, path: &Path, mut progress: F, finish: G) -> Result<()>
+where
+    F: FnMut(u64, Option<u64>) -> bool,
+    G: FnOnce(&Path),
+{
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let part_path = path.with_file_name(format!(
+        "{}.part",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let offset = fs::metadata(&part_path).map_or(0, |m| m.len());
+
+    let mut req = ureq::get(url)
+        .set("User-Agent", BILIBILI_UA)
+        .set("Referer", BILIBILI_REFERER);
+    if offset > 0 {
+        debug!("Resume {} from {} bytes", path.display(), offset);
+        req = req.set("Range", &format!("bytes={}-", offset));
+    }
+    let resp = match req.call() {
+        // The part file is already complete, or bigger than the audio now is
+        Err(ureq::Error::Status(416, _)) => {
+            fs::remove_file(&part_path)?;
+            return download_part(url, path, progress, finish);
         }
+        resp => resp?,
+    };
+
+    // The server may ignore the Range header and send everything again
+    let resumed = resp.status() == 206;
+    let mut received = if resumed { offset } else { 0 };
+    let total = if resumed {
+        resp.header("Content-Range").and_then(content_range_total)
+    } else {
+        resp.header("Content-Length")
+            .and_then(|len| len.parse().ok())
+    };
+
+    let mut dest = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&part_path)?;
+    let mut reader = resp.into_reader();
+    let mut buf = vec![0; CHUNK_SIZE];
+    if !progress(received, total) {
+        return Err(Error::Cancelled);
     }
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        dest.write_all(&buf[..n])?;
+        received += n as u64;
+        if !progress(received, total) {
+            dest.flush()?;
+            return Err(Error::Cancelled);
+        }
+    }
+    dest.flush()?;
+
+    if let Some(total) = total {
+        if received != total {
+            if received > total {
+                fs::remove_file(&part_path)?;
+            }
+            return Err(Error::Incomplete(received, total));
+        }
+    }
+    finish(&part_path);
+    fs::rename(&part_path, path)?;
+    Ok(())
 }
@@ -1,13 +1,47 @@
-use anyhow::{Ok, Result};
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter, Write},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
 };
 
-use serde::{Deserialize, Serialize};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::{audio::SongData, config::CONFIG_FILE};
 
+use super::error::{Error, Result};
+
+/// Envelope shared by every response:
+/// {
+///     "code": 0,
+///     "message": "0",
+///     "ttl": 1,
+///     "data": { ... }
+/// }
+/// `data` is kept as a raw value until `code` has been checked, since
+/// failed requests come with a `data` of a different shape, or none.
+#[derive(Deserialize)]
+pub(crate) struct ApiResponse<T = serde_json::Value> {
+    pub(crate) code: i64,
+    #[serde(default)]
+    pub(crate) message: String,
+    #[serde(default)]
+    #[allow(dead_code)]
+    pub(crate) ttl: i64,
+    pub(crate) data: Option<T>,
+}
+
+impl ApiResponse {
+    pub(crate) fn into_data<T: DeserializeOwned>(self) -> Result<T> {
+        if self.code != 0 {
+            return Err(Error::from_code(self.code, self.message));
+        }
+        let data = self.data.unwrap_or(serde_json::Value::Null);
+        Ok(serde_json::from_value(data)?)
+    }
+}
+
 /// response from bvid:
 /// {
 ///     "code": 0,
@@ -24,6 +58,7 @@ use crate::{audio::SongData, config::CONFIG_FILE};
 ///         "duration": 64,
 ///         "owner": {
 ///             "name": "\u5ed6\u6cfd\u84dd_",
+///             "face": "http://i2.hdslb.com/bfs/face/0c84b9f4ad546d3f20324809d45fc439a2a8ddab.jpg"
 ///         },
 ///         "stat": {
 ///             "aid": 300448445,
@@ -66,7 +101,12 @@ use crate::{audio::SongData, config::CONFIG_FILE};
 /// }
 #[derive(Deserialize)]
 pub struct BvidInfo {
-    data: BiliBiliVideoData,
+    title: String,
+    #[serde(default)]
+    pic: String,
+    owner: Owner,
+    pages: Vec<BiliBiliPageInfo>,
+    ugc_season: Option<UgcSeason>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -85,41 +125,43 @@ struct UgcSeason {
     sections: Vec<Section>,
 }
 
-#[derive(Deserialize)]
-struct BiliBiliVideoData {
-    title: String,
-    owner: Owner,
-    pages: Vec<BiliBiliPageInfo>,
-    ugc_season: Option<UgcSeason>,
-}
-
-#[derive(Deserialize)]
-struct Owner {
-    name: String,
+#[derive(Deserialize, Clone)]
+pub struct Owner {
+    pub name: String,
+    /// Avatar of the uploader
+    #[serde(default)]
+    pub face: String,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct BiliBiliPageInfo {
     pub cid: u32,
+    #[serde(default)]
+    pub page: u32,
     pub part: String,
     pub duration: u64,
 }
 
 impl BvidInfo {
     pub fn get_pages(&self) -> &Vec<BiliBiliPageInfo> {
-        &self.data.pages
+        &self.pages
     }
 
     pub fn get_titile(&self) -> &String {
-        &self.data.title
+        &self.title
     }
 
     pub fn get_author(&self) -> &String {
-        &self.data.owner.name
+        &self.owner.name
+    }
+
+    /// The video cover, or the avatar of the uploader without one
+    pub fn get_cover(&self) -> Option<String> {
+        cover_url(&self.pic).or_else(|| cover_url(&self.owner.face))
     }
 
     pub fn get_episodes(&self) -> Option<Vec<Episode>> {
-        if let Some(season) = &self.data.ugc_season {
+        if let Some(season) = &self.ugc_season {
             let mut vec: Vec<Episode> = Vec::new();
             for i in &season.sections {
                 for j in &i.episodes {
@@ -133,44 +175,720 @@ impl BvidInfo {
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct PlayListData {
+/// Image urls come without a scheme at times, `//i1.hdslb.com/...`
+fn cover_url(url: &str) -> Option<String> {
+    if url.is_empty() {
+        None
+    } else if url.starts_with("//") {
+        Some(format!("https:{}", url))
+    } else {
+        Some(url.to_string())
+    }
+}
+
+/// response from search/type?search_type=video:
+/// {
+///     "code": 0,
+///     "message": "0",
+///     "ttl": 1,
+///     "data": {
+///         "page": 1,
+///         "pagesize": 20,
+///         "numResults": 1000,
+///         "numPages": 50,
+///         "result": [
+///             {
+///                 "type": "video",
+///                 "author": "\u5ed6\u6cfd\u84dd_",
+///                 "bvid": "BV16f4y1o7Q5",
+///                 "title": "\u3010\u7ffb\u5531\u3011<em class=\"keyword\">Welcome to Wonderland</em>",
+///                 "pic": "//i1.hdslb.com/bfs/archive/813b0c3e783b9fa9960c1a1a2ea6bb93055f44e7.jpg",
+///                 "play": 509678,
+///                 "pubdate": 1656648000,
+///                 "duration": "1:04"
+///             }
+///         ]
+///     }
+/// }
+#[derive(Deserialize)]
+pub struct SearchResult {
+    page: u32,
+    #[serde(rename = "numPages")]
+    num_pages: u32,
+    #[serde(default)]
+    result: Vec<SearchVideo>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SearchVideo {
+    pub bvid: String,
+    title: String,
+    pub author: String,
+    pub duration: String,
+    #[serde(default)]
+    pub play: u64,
+    #[serde(default)]
+    pub pubdate: u64,
+}
+
+impl SearchResult {
+    pub fn page(&self) -> u32 {
+        self.page
+    }
+
+    pub fn num_pages(&self) -> u32 {
+        self.num_pages
+    }
+
+    pub fn videos(&self) -> &Vec<SearchVideo> {
+        &self.result
+    }
+}
+
+impl SearchVideo {
+    /// The title with the keyword highlight markup removed
+    pub fn title(&self) -> String {
+        let mut title = String::new();
+        let mut in_tag = false;
+        for c in self.title.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                _ if !in_tag => title.push(c),
+                _ => {}
+            }
+        }
+        title
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&")
+    }
+}
+
+/// response from fav/resource/list:
+/// {
+///     "code": 0,
+///     "message": "0",
+///     "ttl": 1,
+///     "data": {
+///         "info": {
+///             "id": 1052622027,
+///             "title": "\u97f3\u4e50",
+///             "media_count": 2,
+///             "upper": {
+///                 "mid": 2,
+///                 "name": "\u788e\u992a"
+///             }
+///         },
+///         "medias": [
+///             {
+///                 "id": 300448445,
+///                 "type": 2,
+///                 "title": "\u3010\u7ffb\u5531\u3011Welcome to Wonderland - Anson Seabra",
+///                 "page": 1,
+///                 "duration": 64,
+///                 "upper": {
+///                     "mid": 2,
+///                     "name": "\u5ed6\u6cfd\u84dd_"
+///                 },
+///                 "attr": 0,
+///                 "bvid": "BV16f4y1o7Q5",
+///                 "cover": "http://i1.hdslb.com/bfs/archive/813b0c3e783b9fa9960c1a1a2ea6bb93055f44e7.jpg",
+///                 "ugc": {
+///                     "first_cid": 759175760
+///                 }
+///             },
+///             {
+///                 "id": 2,
+///                 "type": 2,
+///                 "title": "\u5df2\u5931\u6548\u89c6\u9891",
+///                 "attr": 9,
+///                 "bvid": "BV1xx411c7mD",
+///             }
+///         ],
+///         "has_more": false
+///     }
+/// }
+#[derive(Deserialize)]
+pub struct FavoriteList {
+    info: FavoriteInfo,
+    medias: Option<Vec<FavoriteMedia>>,
+    has_more: bool,
+}
+
+#[derive(Deserialize)]
+struct FavoriteInfo {
+    title: String,
+    media_count: u32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FavoriteUgc {
+    pub first_cid: u32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FavoriteMedia {
+    #[serde(rename = "type")]
+    pub media_type: u32,
+    pub title: String,
+    pub bvid: String,
+    #[serde(default)]
+    pub page: u32,
+    #[serde(default)]
+    pub duration: u64,
+    pub upper: Option<Owner>,
+    #[serde(default)]
+    pub cover: String,
+    pub attr: u32,
+    pub ugc: Option<FavoriteUgc>,
+}
+
+impl FavoriteList {
+    pub fn title(&self) -> &String {
+        &self.info.title
+    }
+
+    pub fn media_count(&self) -> u32 {
+        self.info.media_count
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.has_more
+    }
+
+    pub fn into_medias(self) -> Vec<FavoriteMedia> {
+        self.medias.unwrap_or_default()
+    }
+}
+
+impl FavoriteMedia {
+    /// Only videos (type 2) can be played, attr is non-zero when the
+    /// video has been deleted or hidden by its uploader
+    pub fn is_valid(&self) -> bool {
+        self.media_type == 2 && self.attr == 0
+    }
+
+    /// The video cover, or the avatar of the uploader without one
+    pub fn cover(&self) -> Option<String> {
+        cover_url(&self.cover).or_else(|| cover_url(&self.upper.as_ref()?.face))
+    }
+}
+
+/// Sort order of search results
+#[derive(Clone, Copy, PartialEq)]
+pub enum SearchOrder {
+    Relevance,
+    PlayCount,
+    UploadDate,
+}
+
+impl SearchOrder {
+    pub fn from_index(index: u32) -> Self {
+        match index {
+            1 => SearchOrder::PlayCount,
+            2 => SearchOrder::UploadDate,
+            _ => SearchOrder::Relevance,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchOrder::Relevance => "totalrank",
+            SearchOrder::PlayCount => "click",
+            SearchOrder::UploadDate => "pubdate",
+        }
+    }
+}
+
+/// Where playback was left off, so it can be picked up after a restart
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PlaybackData {
+    /// bvid and cid of the current song
+    pub current: Option<(String, u32)>,
+    /// Elapsed seconds of the current song
+    pub position: u64,
+    pub volume: f64,
+    /// The order of a shuffled queue, as positions in `data`
+    pub shuffle: Option<Vec<u32>>,
+}
+
+impl Default for PlaybackData {
+    fn default() -> Self {
+        PlaybackData {
+            current: None,
+            position: 0,
+            volume: 1.0,
+            shuffle: None,
+        }
+    }
+}
+
+/// Name of the playlist the songs of a single list config end up in
+pub const DEFAULT_PLAYLIST: &str = "默认列表";
+
+/// Version of the config document, which is read once to import it into
+/// the library:
+/// 1. `{"data": [...]}`, a single list of songs, without a version field
+/// 2. named playlists and where playback was left off
+pub const CONFIG_VERSION: u64 = 2;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Playlist {
+    pub name: String,
     pub data: Vec<SongData>,
 }
 
-pub fn parse_config() -> Result<Vec<SongData>> {
-    let file = File::open(&*CONFIG_FILE)?;
-    let buf_reader = BufReader::new(file);
-    let config: PlayListData = serde_json::from_reader(buf_reader)?;
-    Ok(config.data)
+/// Everything in config.json
+#[derive(Deserialize, Serialize)]
+pub struct PlayListData {
+    #[serde(default)]
+    pub version: u64,
+    pub playlists: Vec<Playlist>,
+    /// The playlist which is loaded into the queue
+    #[serde(default)]
+    pub active: usize,
+    #[serde(default)]
+    pub playback: PlaybackData,
+}
+
+impl Default for PlayListData {
+    fn default() -> Self {
+        let playlist = Playlist {
+            name: DEFAULT_PLAYLIST.to_string(),
+            data: Vec::new(),
+        };
+        Self::new(vec![playlist], 0, PlaybackData::default())
+    }
+}
+
+impl PlayListData {
+    pub fn new(playlists: Vec<Playlist>, active: usize, playback: PlaybackData) -> Self {
+        PlayListData {
+            version: CONFIG_VERSION,
+            playlists,
+            active,
+            playback,
+        }
+    }
+
+    /// Parse a config document of any version up to CONFIG_VERSION
+    fn from_document(mut doc: Value) -> Result<Self> {
+        let mut version = document_version(&doc);
+        if version == 0 || version > CONFIG_VERSION {
+            return Err(Error::Invalid(format!("不支持的配置文件版本: {}", version)));
+        }
+        while version < CONFIG_VERSION {
+            doc = MIGRATIONS[version as usize - 1](doc)?;
+            version += 1;
+        }
+
+        let mut list: PlayListData = serde_json::from_value(doc)?;
+        list.version = CONFIG_VERSION;
+        if list.playlists.is_empty() {
+            list.playlists = PlayListData::default().playlists;
+        }
+        if list.active >= list.playlists.len() {
+            list.active = 0;
+        }
+        Ok(list)
+    }
+}
+
+/// Documents without a version field are either version 1, or version 2
+/// from before the field was added
+fn document_version(doc: &Value) -> u64 {
+    match doc.get("version").and_then(Value::as_u64) {
+        Some(version) => version,
+        None if doc.get("playlists").is_some() => 2,
+        None => 1,
+    }
+}
+
+/// `MIGRATIONS[n - 1]` turns a version n document into version n + 1
+const MIGRATIONS: [fn(Value) -> Result<Value>; CONFIG_VERSION as usize - 1] = [migrate_v1];
+
+/// The single list becomes the default playlist
+fn migrate_v1(doc: Value) -> Result<Value> {
+    let mut doc = match doc {
+        Value::Object(doc) => doc,
+        _ => return Err(Error::Invalid("配置文件格式错误".to_string())),
+    };
+    let data = doc.remove("data").unwrap_or_else(|| json!([]));
+    doc.insert(
+        "playlists".to_string(),
+        json!([{ "name": DEFAULT_PLAYLIST, "data": data }]),
+    );
+    doc.insert("active".to_string(), json!(0));
+    doc.insert("version".to_string(), json!(2));
+    Ok(Value::Object(doc))
+}
+
+/// The last config that could be read, taken once per launch
+fn backup_path(path: &Path) -> PathBuf {
+    path.with_extension("json.bak")
+}
+
+/// Where a config that can not be read is kept for inspection
+fn corrupt_path(path: &Path) -> PathBuf {
+    path.with_extension("json.corrupt")
+}
+
+fn read_config(path: &Path) -> Result<PlayListData> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    // CONFIG_FILE starts out as an empty file
+    if text.trim().is_empty() {
+        return Ok(PlayListData::default());
+    }
+    PlayListData::from_document(serde_json::from_str(&text)?)
+}
+
+/// Read the config at `path`; when that fails it is moved aside and the
+/// backup is restored instead, so that the next write does not lose it
+fn parse_config_file(path: &Path) -> Result<PlayListData> {
+    match read_config(path) {
+        Ok(list) => {
+            if path.exists() {
+                if let Err(e) = fs::copy(path, backup_path(path)) {
+                    warn!("Failed to back up {}: {}", path.display(), e);
+                }
+            }
+            Ok(list)
+        }
+        Err(e) => {
+            warn!("Failed to read {}: {}", path.display(), e);
+            fs::rename(path, corrupt_path(path))?;
+            let backup = backup_path(path);
+            if !backup.exists() {
+                return Err(e);
+            }
+            let list = read_config(&backup).map_err(|_| e)?;
+            fs::copy(&backup, path)?;
+            Ok(list)
+        }
+    }
+}
+
+pub fn parse_config() -> Result<PlayListData> {
+    parse_config_file(&CONFIG_FILE)
+}
+
+/// Audio quality ids of the playurl API, from worst to best
+pub const AUDIO_QUALITIES: [(u32, &str); 5] = [
+    (30216, "64K"),
+    (30232, "132K"),
+    (30280, "192K"),
+    (30250, "杜比全景声"),
+    (30251, "Hi-Res 无损"),
+];
+
+/// One entry of `dash.audio`, `dash.dolby.audio` or `dash.flac.audio`
+#[derive(Deserialize, Clone, Debug)]
+pub struct AudioStream {
+    pub id: u32,
+    #[serde(rename = "baseUrl", alias = "base_url")]
+    pub base_url: String,
+    #[serde(default)]
+    pub bandwidth: u64,
+    #[serde(rename = "mimeType", alias = "mime_type", default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub codecs: String,
+}
+
+impl AudioStream {
+    /// Known qualities sort by their tier, the rest by bandwidth
+    fn rank(&self) -> (usize, u64) {
+        let tier = AUDIO_QUALITIES
+            .iter()
+            .position(|(id, _)| *id == self.id)
+            .map_or(0, |pos| pos + 1);
+        (tier, self.bandwidth)
+    }
+
+    /// File extension for the container of the stream. Even flac and
+    /// dolby audio are delivered as fragmented mp4.
+    pub fn extension(&self) -> &'static str {
+        match self.mime_type.as_str() {
+            "audio/flac" => "flac",
+            "audio/webm" => "webm",
+            _ => "m4a",
+        }
+    }
 }
 
-pub fn write_config(data: Vec<SongData>) -> Result<()> {
-    let file = File::create(&*CONFIG_FILE)?;
-    let mut buf_writer = BufWriter::new(file);
-    let list = PlayListData { data };
-    let s = serde_json::to_vec(&list)?;
-    buf_writer.write(&s)?;
-    buf_writer.flush()?;
-    Ok(())
+/// Which of the available audio streams to download
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityPreference {
+    Highest,
+    /// The given quality id, or the best one below it
+    Tier(u32),
+    /// Save data on metered connections
+    Lowest,
+}
+
+impl Default for QualityPreference {
+    fn default() -> Self {
+        QualityPreference::Highest
+    }
+}
+
+impl QualityPreference {
+    /// `highest`, `lowest` or a quality id, used as the target of win.quality
+    pub fn to_action_target(&self) -> String {
+        match self {
+            QualityPreference::Highest => "highest".to_string(),
+            QualityPreference::Lowest => "lowest".to_string(),
+            QualityPreference::Tier(id) => id.to_string(),
+        }
+    }
+
+    pub fn from_action_target(target: &str) -> Option<Self> {
+        match target {
+            "highest" => Some(QualityPreference::Highest),
+            "lowest" => Some(QualityPreference::Lowest),
+            id => id.parse().ok().map(QualityPreference::Tier),
+        }
+    }
+
+    pub fn select(&self, streams: Vec<AudioStream>) -> Option<AudioStream> {
+        match self {
+            QualityPreference::Highest => streams.into_iter().max_by_key(AudioStream::rank),
+            QualityPreference::Lowest => streams.into_iter().min_by_key(AudioStream::rank),
+            QualityPreference::Tier(id) => {
+                if let Some(stream) = streams.iter().find(|s| s.id == *id) {
+                    return Some(stream.clone());
+                }
+                let tier = AUDIO_QUALITIES
+                    .iter()
+                    .position(|(q, _)| q == id)
+                    .map_or(0, |pos| pos + 1);
+                let below = streams
+                    .iter()
+                    .filter(|s| s.rank().0 < tier)
+                    .max_by_key(|s| s.rank())
+                    .cloned();
+                below.or_else(|| streams.into_iter().min_by_key(AudioStream::rank))
+            }
+        }
+    }
 }
 
 #[derive(Deserialize)]
-pub struct BiliBiliSong {
-    pub(super) baseUrl: String,
+pub(crate) struct DolbyAudio {
+    pub(crate) audio: Option<Vec<AudioStream>>,
 }
 
 #[derive(Deserialize)]
-pub(crate) struct Dash {
-    pub(crate) audio: Vec<BiliBiliSong>,
+pub(crate) struct FlacAudio {
+    pub(crate) audio: Option<AudioStream>,
 }
 
 #[derive(Deserialize)]
-pub(crate) struct PlayUrlData {
-    pub(crate) dash: Dash,
+pub(crate) struct Dash {
+    pub(crate) audio: Option<Vec<AudioStream>>,
+    pub(crate) dolby: Option<DolbyAudio>,
+    pub(crate) flac: Option<FlacAudio>,
+}
+
+impl Dash {
+    /// Every audio stream, including the dolby and flac ones
+    pub(crate) fn into_streams(self) -> Vec<AudioStream> {
+        let mut streams = self.audio.unwrap_or_default();
+        if let Some(audio) = self.dolby.and_then(|dolby| dolby.audio) {
+            streams.extend(audio);
+        }
+        if let Some(audio) = self.flac.and_then(|flac| flac.audio) {
+            streams.push(audio);
+        }
+        streams
+    }
 }
 
 #[derive(Deserialize)]
 pub(crate) struct PlayUrl {
-    pub(crate) data: PlayUrlData,
+    pub(crate) dash: Dash,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_api_response() {
+        let ok = r#"{"code":0,"message":"0","ttl":1,"data":{"dash":{"audio":[{"id":30280,"baseUrl":"https://upos/1.m4s"}]}}}"#;
+        let resp: ApiResponse = serde_json::from_str(ok).unwrap();
+        let play_url: PlayUrl = resp.into_data().unwrap();
+        assert_eq!(
+            play_url.dash.into_streams()[0].base_url,
+            "https://upos/1.m4s"
+        );
+
+        let locked = r#"{"code":-10403,"message":"抱歉您所在地区不可观看！","ttl":1,"data":{}}"#;
+        let resp: ApiResponse = serde_json::from_str(locked).unwrap();
+        assert!(matches!(
+            resp.into_data::<PlayUrl>(),
+            Err(Error::RegionLocked)
+        ));
+
+        let invisible = r#"{"code":62002,"message":"稿件不可见","ttl":1}"#;
+        let resp: ApiResponse = serde_json::from_str(invisible).unwrap();
+        let err = resp.into_data::<BvidInfo>().err().unwrap();
+        assert!(err.is_unavailable());
+    }
+
+    #[test]
+    fn test_select_quality() {
+        let dash = r#"{
+            "audio": [
+                {"id": 30216, "baseUrl": "64", "bandwidth": 67000, "mimeType": "audio/mp4", "codecs": "mp4a.40.2"},
+                {"id": 30280, "baseUrl": "192", "bandwidth": 190000, "mimeType": "audio/mp4", "codecs": "mp4a.40.2"},
+                {"id": 30232, "baseUrl": "132", "bandwidth": 130000, "mimeType": "audio/mp4", "codecs": "mp4a.40.2"}
+            ],
+            "dolby": {"type": 1, "audio": [
+                {"id": 30250, "baseUrl": "dolby", "bandwidth": 448000, "mimeType": "audio/mp4", "codecs": "ec-3"}
+            ]},
+            "flac": {"display": true, "audio": null}
+        }"#;
+        let streams = serde_json::from_str::<Dash>(dash).unwrap().into_streams();
+        let select =
+            |preference: QualityPreference| preference.select(streams.clone()).unwrap().base_url;
+
+        assert_eq!(select(QualityPreference::Highest), "dolby");
+        assert_eq!(select(QualityPreference::Lowest), "64");
+        assert_eq!(select(QualityPreference::Tier(30232)), "132");
+        // No flac stream, fall back to the best one below it
+        assert_eq!(select(QualityPreference::Tier(30251)), "dolby");
+        assert_eq!(
+            QualityPreference::from_action_target("30280"),
+            Some(QualityPreference::Tier(30280))
+        );
+    }
+
+    #[test]
+    fn test_cover() {
+        let info = r#"{
+            "title": "video",
+            "pic": "//i1.hdslb.com/bfs/archive/cover.jpg",
+            "owner": {"name": "up", "face": "http://i2.hdslb.com/bfs/face/face.jpg"},
+            "pages": []
+        }"#;
+        let mut info: BvidInfo = serde_json::from_str(info).unwrap();
+        assert_eq!(
+            info.get_cover().unwrap(),
+            "https://i1.hdslb.com/bfs/archive/cover.jpg"
+        );
+        info.pic.clear();
+        assert_eq!(
+            info.get_cover().unwrap(),
+            "http://i2.hdslb.com/bfs/face/face.jpg"
+        );
+    }
+
+    fn fixture_text(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/config")
+            .join(name);
+        fs::read_to_string(path).unwrap()
+    }
+
+    fn fixture(name: &str) -> Value {
+        serde_json::from_str(&fixture_text(name)).unwrap()
+    }
+
+    /// A config.json of its own for each test
+    fn config_path(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bilibili-music-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("config.json")
+    }
+
+    #[test]
+    fn test_config_v1() {
+        let list = PlayListData::from_document(fixture("v1.json")).unwrap();
+        assert_eq!(list.version, CONFIG_VERSION);
+        assert_eq!(list.playlists.len(), 1);
+        assert_eq!(list.playlists[0].name, DEFAULT_PLAYLIST);
+        assert_eq!(list.playlists[0].data.len(), 2);
+        assert_eq!(list.playlists[0].data[1].title(), "other");
+        assert_eq!(list.active, 0);
+        assert_eq!(list.playback, PlaybackData::default());
+
+        // The old list is not written back
+        let json = serde_json::to_value(&list).unwrap();
+        assert!(json.get("data").is_none());
+    }
+
+    #[test]
+    fn test_config_v2() {
+        let list = PlayListData::from_document(fixture("v2.json")).unwrap();
+        assert_eq!(list.playlists.len(), 2);
+        assert_eq!(list.playlists[1].name, "工作");
+        assert_eq!(list.playlists[1].data[0].title(), "song");
+        assert_eq!(list.active, 1);
+        assert_eq!(list.playback.position, 42);
+        assert_eq!(list.playback.current, Some(("BV1xx411c7mD".to_string(), 1)));
+
+        // Written before the version field was added
+        let mut doc = fixture("v2.json");
+        doc.as_object_mut().unwrap().remove("version");
+        let list = PlayListData::from_document(doc).unwrap();
+        assert_eq!(list.playlists.len(), 2);
+
+        assert!(PlayListData::from_document(fixture("future.json")).is_err());
+    }
+
+    #[test]
+    fn test_read_config() {
+        let path = config_path("read");
+        // The empty file CONFIG_FILE creates
+        fs::write(&path, "").unwrap();
+        let list = parse_config_file(&path).unwrap();
+        assert_eq!(list.playlists[0].name, DEFAULT_PLAYLIST);
+
+        let playback = PlaybackData {
+            current: Some(("BV1xx411c7mD".to_string(), 1176840)),
+            position: 42,
+            volume: 0.5,
+            shuffle: Some(vec![2, 0, 1]),
+        };
+        let list = PlayListData::from_document(fixture("v1.json")).unwrap();
+        let list = PlayListData::new(list.playlists, 0, playback.clone());
+        fs::write(&path, serde_json::to_string(&list).unwrap()).unwrap();
+
+        let parsed = parse_config_file(&path).unwrap();
+        assert_eq!(parsed.playlists, list.playlists);
+        assert_eq!(parsed.playback, playback);
+    }
+
+    #[test]
+    fn test_config_recovery() {
+        let path = config_path("recovery");
+        let v2 = fixture_text("v2.json");
+        let corrupt = fixture_text("corrupt.json");
+
+        // Nothing to recover from
+        fs::write(&path, &corrupt).unwrap();
+        assert!(parse_config_file(&path).is_err());
+        assert_eq!(fs::read_to_string(corrupt_path(&path)).unwrap(), corrupt);
+
+        // Reading a good config leaves a backup behind
+        fs::write(&path, &v2).unwrap();
+        parse_config_file(&path).unwrap();
+        fs::write(&path, &corrupt).unwrap();
+        let list = parse_config_file(&path).unwrap();
+        assert_eq!(list.playlists[1].name, "工作");
+        assert_eq!(fs::read_to_string(&path).unwrap(), v2);
+        assert_eq!(fs::read_to_string(corrupt_path(&path)).unwrap(), corrupt);
+    }
 }
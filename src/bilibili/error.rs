@@ -0,0 +1,128 @@
+use std::fmt;
+
+/// Everything that can go wrong while talking to Bilibili or touching
+/// the files we keep for it
+#[derive(Debug)]
+pub enum Error {
+    /// The request never got a response
+    Network(String),
+    /// The server answered with an HTTP error
+    HttpStatus(u16, String),
+    /// -404, the video does not exist or has been deleted
+    NotFound,
+    /// 62002/62012, the video is hidden by its uploader
+    Invisible,
+    /// 62004, the video is still under review
+    Reviewing,
+    /// -10403, the video is not available in this region
+    RegionLocked,
+    /// -403, the request needs permissions we do not have
+    Forbidden,
+    /// -412, the request was blocked by the risk control
+    RateLimited,
+    /// The API answered with any other `code != 0`
+    Api(i64, String),
+    /// The response is not what we expected
+    Json(serde_json::Error),
+    /// The download ended before all of the audio arrived
+    Incomplete(u64, u64),
+    /// The download was stopped before it finished
+    Cancelled,
+    /// Reading or writing the cache or config failed
+    Io(std::io::Error),
+    /// The library database could not be read or written
+    Database(rusqlite::Error),
+    /// The input does not point to anything we can play
+    Invalid(String),
+    /// Offline mode is on, nothing goes out to the network
+    Offline,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn from_code(code: i64, message: String) -> Self {
+        match code {
+            -404 => Error::NotFound,
+            62002 | 62012 => Error::Invisible,
+            62004 => Error::Reviewing,
+            -10403 => Error::RegionLocked,
+            -403 => Error::Forbidden,
+            -412 => Error::RateLimited,
+            _ => Error::Api(code, message),
+        }
+    }
+
+    /// The video itself can not be played, retrying will not help
+    pub fn is_unavailable(&self) -> bool {
+        matches!(
+            self,
+            Error::NotFound | Error::Invisible | Error::Reviewing | Error::RegionLocked
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "网络错误: {}", e),
+            Error::HttpStatus(status, url) => write!(f, "HTTP {}: {}", status, url),
+            Error::NotFound => write!(f, "视频不存在或已被删除"),
+            Error::Invisible => write!(f, "视频不可见"),
+            Error::Reviewing => write!(f, "视频审核中"),
+            Error::RegionLocked => write!(f, "视频在当前地区不可用"),
+            Error::Forbidden => write!(f, "没有访问权限"),
+            Error::RateLimited => write!(f, "请求过于频繁，已被拦截"),
+            Error::Api(code, message) => write!(f, "接口错误 {}: {}", code, message),
+            Error::Json(e) => write!(f, "无法解析返回数据: {}", e),
+            Error::Incomplete(received, total) => {
+                write!(f, "下载不完整: {}/{} 字节", received, total)
+            }
+            Error::Cancelled => write!(f, "下载已取消"),
+            Error::Io(e) => write!(f, "文件错误: {}", e),
+            Error::Database(e) => write!(f, "数据库错误: {}", e),
+            Error::Invalid(message) => write!(f, "{}", message),
+            Error::Offline => write!(f, "离线模式，无法连接网络"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, resp) => {
+                Error::HttpStatus(status, resp.get_url().to_string())
+            }
+            ureq::Error::Transport(t) => Error::Network(t.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Database(e)
+    }
+}
@@ -8,6 +8,7 @@ use crate::{
 mod imp {
     use super::*;
     use gtk::{CompositeTemplate, TemplateChild};
+    use std::cell::RefCell;
 
     // BvidInputView
     #[derive(CompositeTemplate, Default)]
@@ -16,7 +17,9 @@ mod imp {
         #[template_child]
         pub confirm_btn: TemplateChild<gtk::Button>,
         #[template_child]
-        pub bv_input: TemplateChild<gtk::Entry>,
+        pub bv_input: TemplateChild<gtk::TextView>,
+        #[template_child]
+        pub error_label: TemplateChild<gtk::Label>,
     }
 
     #[glib::object_subclass]
@@ -50,7 +53,13 @@ mod imp {
         pub confirm: TemplateChild<gtk::Button>,
         #[template_child]
         pub cancel: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub status_label: TemplateChild<gtk::Label>,
+        #[template_child]
+        pub playlist_dropdown: TemplateChild<gtk::DropDown>,
         pub queue: Queue,
+        /// Shown next to the song at the same position
+        pub details: RefCell<Vec<String>>,
     }
     #[glib::object_subclass]
     impl ObjectSubclass for SongListView {
@@ -75,7 +84,10 @@ mod imp {
                 songs_view: TemplateChild::default(),
                 confirm: TemplateChild::default(),
                 cancel: TemplateChild::default(),
+                status_label: TemplateChild::default(),
+                playlist_dropdown: TemplateChild::default(),
                 queue,
+                details: RefCell::new(Vec::new()),
             }
         }
     }
@@ -88,7 +100,7 @@ mod imp {
 
 glib::wrapper! {
     pub struct BvidInputView(ObjectSubclass<imp::BvidInputView>)
-        @extends gtk::Widget,
+        @extends gtk::Widget, gtk::Popover,
         @implements gio::ActionGroup, gio::ActionMap;
 }
 
@@ -107,12 +119,27 @@ impl BvidInputView {
         self.imp().confirm_btn.get()
     }
 
-    pub fn get_input_bvid(&self) -> String {
+    /// Take the text out of the input, the popover stays open until
+    /// the input is known to be valid
+    pub fn take_input(&self) -> String {
         let buffer = self.imp().bv_input.buffer();
-        let bvid = buffer.text();
-        buffer.delete_text(0, None);
-        self.set_visible(false);
-        bvid
+        let (start, end) = buffer.bounds();
+        let text = buffer.text(&start, &end, false).to_string();
+        buffer.set_text("");
+        self.imp().error_label.set_visible(false);
+        text
+    }
+
+    /// Put the lines that could not be parsed back for editing
+    pub fn show_failed(&self, failed: &[String]) {
+        self.imp().bv_input.buffer().set_text(&failed.join("\n"));
+        self.show_error(&format!("无法识别: {}", failed.join(", ")));
+    }
+
+    fn show_error(&self, message: &str) {
+        let label = self.imp().error_label.get();
+        label.set_label(message);
+        label.set_visible(true);
     }
 }
 
@@ -142,6 +169,13 @@ impl SongListView {
                 .chain_property::<Song>("selected")
                 .bind(&row, "selected", gtk::Widget::NONE);
         });
+        factory.connect_bind(glib::clone!(@weak self as this => move |_, list_item| {
+            if let Some(row) = list_item.child().and_then(|c| c.downcast::<SongRow>().ok()) {
+                let details = this.imp().details.borrow();
+                let detail = details.get(list_item.position() as usize);
+                row.set_detail(detail.map(|detail| detail.as_str()));
+            }
+        }));
         let view = self.imp().songs_view.get();
         view.set_factory(Some(&factory));
 
@@ -174,6 +208,36 @@ impl SongListView {
         self.imp().cancel.get()
     }
 
+    /// Offer the playlists to add the songs to, `active` is preselected
+    pub fn set_playlists(&self, playlists: &gio::ListModel, active: u32) {
+        let dropdown = self.imp().playlist_dropdown.get();
+        dropdown.set_model(Some(playlists));
+        dropdown.set_selected(active);
+    }
+
+    /// The playlist the selected songs go to
+    pub fn target_playlist(&self) -> u32 {
+        self.imp().playlist_dropdown.selected()
+    }
+
+    /// Show `details` next to the songs, in the order they were passed to
+    /// init
+    pub fn set_details(&self, details: Vec<String>) {
+        self.imp().details.replace(details);
+    }
+
+    /// Report the entries that could not be added
+    pub fn set_invalid(&self, invalid: &[String]) {
+        let label = self.imp().status_label.get();
+        if invalid.is_empty() {
+            label.set_label("");
+            label.set_tooltip_text(None);
+        } else {
+            label.set_label(&format!("{} 个视频已失效", invalid.len()));
+            label.set_tooltip_text(Some(&invalid.join("\n")));
+        }
+    }
+
     pub fn selected_songs(&self) -> Option<Vec<Song>> {
         let queue = self.queue();
         if queue.is_empty() {
@@ -1,7 +1,16 @@
 mod api;
 pub mod data;
+mod error;
 mod input;
+mod parser;
+mod search;
 
-pub use api::{download_song, get_url, remove_cache};
+pub use api::{
+    download_song, get_favorite_medias, get_url, search_video, set_offline, BILIBILI_REFERER,
+    BILIBILI_UA,
+};
+pub use error::{Error, Result};
 pub use input::BvidInputView;
 pub use input::SongListView;
+pub use parser::{parse_input, resolve_short_link, BiliInput};
+pub use search::SearchView;
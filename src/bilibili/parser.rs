@@ -0,0 +1,237 @@
+use super::error::{Error, Result};
+
+/// One entry of the input popover
+#[derive(Debug, PartialEq, Clone)]
+pub enum BiliInput {
+    /// bvid and the page selected with `?p=`, starting from 1
+    Video(String, Option<u32>),
+    /// b23.tv link which has to be resolved through its redirect
+    ShortLink(String),
+    /// media_id of a favorites folder
+    Favorites(u64),
+}
+
+// https://github.com/SocialSisterYi/bilibili-API-collect/blob/master/docs/misc/bvid_desc.md
+const XOR_CODE: u64 = 23442827791579;
+const MASK_CODE: u64 = 2251799813685247;
+const MAX_AID: u64 = 1 << 51;
+const BASE: u64 = 58;
+const BV_LEN: usize = 12;
+const ALPHABET: &[u8] = b"FcwAPNKTMug3GV5Lj7EJnHpWsx4tb8haYeviqBz6rkCy12mUSDQX9RdoZf";
+
+pub fn av_to_bv(aid: u64) -> String {
+    let mut bytes: Vec<u8> = b"BV1000000000".to_vec();
+    let mut idx = BV_LEN - 1;
+    let mut tmp = (MAX_AID | aid) ^ XOR_CODE;
+    while tmp > 0 {
+        bytes[idx] = ALPHABET[(tmp % BASE) as usize];
+        tmp /= BASE;
+        idx -= 1;
+    }
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    String::from_utf8(bytes).unwrap()
+}
+
+pub fn bv_to_av(bvid: &str) -> Option<u64> {
+    let mut bytes: Vec<u8> = normalize_bvid(bvid)?.into_bytes();
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    let mut tmp: u64 = 0;
+    for c in &bytes[3..] {
+        let pos = ALPHABET.iter().position(|a| a == c)? as u64;
+        tmp = tmp * BASE + pos;
+    }
+    Some((tmp & MASK_CODE) ^ XOR_CODE)
+}
+
+/// `bv1xx411c7mD` -> `BV1xx411c7mD`, None if it is not a bvid
+fn normalize_bvid(s: &str) -> Option<String> {
+    if s.len() != BV_LEN || !s.is_char_boundary(2) || !s[..2].eq_ignore_ascii_case("bv") {
+        return None;
+    }
+    let id = &s[2..];
+    if id.bytes().all(|c| ALPHABET.contains(&c)) {
+        Some(format!("BV{}", id))
+    } else {
+        None
+    }
+}
+
+/// `av170001` -> aid
+fn parse_aid(s: &str) -> Option<u64> {
+    if s.len() > 2 && s.is_char_boundary(2) && s[..2].eq_ignore_ascii_case("av") {
+        s[2..].parse().ok()
+    } else {
+        None
+    }
+}
+
+fn parse_video_id(id: &str, page: Option<u32>) -> Option<BiliInput> {
+    if let Some(bvid) = normalize_bvid(id) {
+        return Some(BiliInput::Video(bvid, page));
+    }
+    parse_aid(id).map(|aid| BiliInput::Video(av_to_bv(aid), page))
+}
+
+fn query_value<'a>(url: &'a str, key: &str) -> Option<&'a str> {
+    let query = url.split_once('?')?.1;
+    let query = query.split('#').next().unwrap();
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+fn leading_digits(s: &str) -> Option<u64> {
+    let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Favorites folder URLs look like
+/// https://space.bilibili.com/2/favlist?fid=1052622027 or
+/// https://www.bilibili.com/medialist/detail/ml1052622027
+fn parse_media_id(url: &str) -> Option<u64> {
+    if let Some(fid) = query_value(url, "fid") {
+        return leading_digits(fid);
+    }
+    if let Some(pos) = url.find("/ml") {
+        return leading_digits(&url[pos + 3..]);
+    }
+    None
+}
+
+fn parse_url(url: &str) -> Option<BiliInput> {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let (host, path) = without_scheme
+        .split_once('/')
+        .unwrap_or((without_scheme, ""));
+
+    if host == "b23.tv" || host.ends_with(".b23.tv") {
+        if path.is_empty() {
+            return None;
+        }
+        return Some(BiliInput::ShortLink(format!("https://{}", without_scheme)));
+    }
+
+    if host != "bilibili.com" && !host.ends_with(".bilibili.com") {
+        return None;
+    }
+
+    if let Some(pos) = path.find("video/") {
+        let id = path[pos + 6..].split(['/', '?', '#']).next().unwrap();
+        let page = query_value(url, "p").and_then(|p| p.parse().ok());
+        return parse_video_id(id, page);
+    }
+
+    parse_media_id(url).map(BiliInput::Favorites)
+}
+
+/// Parse a single id or URL
+pub fn parse_line(line: &str) -> Result<BiliInput> {
+    let line = line.trim();
+    if let Ok(media_id) = line.parse::<u64>() {
+        return Ok(BiliInput::Favorites(media_id));
+    }
+    if let Some(input) = parse_video_id(line, None) {
+        return Ok(input);
+    }
+    if line.contains("b23.tv") || line.contains("bilibili.com") {
+        if let Some(input) = parse_url(line) {
+            return Ok(input);
+        }
+    }
+    Err(Error::Invalid(format!("无法识别: {}", line)))
+}
+
+/// Parse everything pasted into the popover, ids and URLs are separated
+/// by newlines or spaces. Returns the entries and the lines that failed.
+pub fn parse_input(text: &str) -> (Vec<BiliInput>, Vec<String>) {
+    let mut inputs = Vec::new();
+    let mut failed = Vec::new();
+    for word in text.split_whitespace() {
+        match parse_line(word) {
+            Ok(input) => inputs.push(input),
+            Err(_) => failed.push(word.to_string()),
+        }
+    }
+    (inputs, failed)
+}
+
+/// Follow the redirect of a b23.tv link without loading the target page
+pub fn resolve_short_link(url: &str) -> Result<BiliInput> {
+    super::api::check_online()?;
+    let agent = ureq::AgentBuilder::new().redirects(0).build();
+    let resp = agent.get(url).call()?;
+    let location = resp
+        .header("Location")
+        .ok_or_else(|| Error::Invalid(format!("{} 没有跳转", url)))?;
+    match parse_url(location) {
+        Some(BiliInput::ShortLink(_)) | None => {
+            Err(Error::Invalid(format!("无法识别: {}", location)))
+        }
+        Some(input) => Ok(input),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_av_bv() {
+        assert_eq!(av_to_bv(170001), "BV17x411w7KC");
+        assert_eq!(av_to_bv(300448445), "BV16f4y1o7Q5");
+        assert_eq!(bv_to_av("BV17x411w7KC"), Some(170001));
+        assert_eq!(bv_to_av("bv16f4y1o7Q5"), Some(300448445));
+        assert_eq!(bv_to_av("BV16f4y1o7Q"), None);
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let video = |bvid: &str, page| BiliInput::Video(bvid.to_string(), page);
+
+        assert_eq!(
+            parse_line("BV16f4y1o7Q5").unwrap(),
+            video("BV16f4y1o7Q5", None)
+        );
+        assert_eq!(
+            parse_line(" av170001 ").unwrap(),
+            video("BV17x411w7KC", None)
+        );
+        assert_eq!(
+            parse_line("https://www.bilibili.com/video/BV16f4y1o7Q5?p=3&vd_source=abc").unwrap(),
+            video("BV16f4y1o7Q5", Some(3))
+        );
+        assert_eq!(
+            parse_line("https://m.bilibili.com/video/av170001/").unwrap(),
+            video("BV17x411w7KC", None)
+        );
+        assert_eq!(
+            parse_line("https://b23.tv/abcDEF").unwrap(),
+            BiliInput::ShortLink("https://b23.tv/abcDEF".to_string())
+        );
+        assert_eq!(
+            parse_line("https://space.bilibili.com/2/favlist?fid=1052622027&ftype=create").unwrap(),
+            BiliInput::Favorites(1052622027)
+        );
+        assert_eq!(
+            parse_line("https://www.bilibili.com/medialist/detail/ml1052622027").unwrap(),
+            BiliInput::Favorites(1052622027)
+        );
+        assert_eq!(
+            parse_line("1052622027").unwrap(),
+            BiliInput::Favorites(1052622027)
+        );
+        assert!(parse_line("https://example.com/video/BV16f4y1o7Q5").is_err());
+        assert!(parse_line("hello").is_err());
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let (inputs, failed) = parse_input("BV16f4y1o7Q5\n\nav170001\r\nnot-an-id\n");
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(failed, vec!["not-an-id"]);
+    }
+}
@@ -0,0 +1,243 @@
+use std::sync::Arc;
+
+use gtk::{
+    gio,
+    glib::{self, clone, Sender},
+    prelude::*,
+    subclass::prelude::*,
+};
+use log::warn;
+
+use crate::utils;
+
+use super::{
+    data::{SearchOrder, SearchResult, SearchVideo},
+    search_video, Result,
+};
+
+mod imp {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+    use gtk::{CompositeTemplate, TemplateChild};
+
+    #[derive(CompositeTemplate, Default)]
+    #[template(resource = "/org/bilibili/music/search-view.ui")]
+    pub struct SearchView {
+        #[template_child]
+        pub search_entry: TemplateChild<gtk::SearchEntry>,
+        #[template_child]
+        pub order_dropdown: TemplateChild<gtk::DropDown>,
+        #[template_child]
+        pub results_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
+        pub prev_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub next_btn: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub page_label: TemplateChild<gtk::Label>,
+
+        pub keyword: RefCell<String>,
+        pub page: Cell<u32>,
+        pub num_pages: Cell<u32>,
+        pub results: RefCell<Vec<SearchVideo>>,
+        /// Bumped by every search, results of the earlier ones are dropped
+        pub generation: Cell<u32>,
+        pub tx: RefCell<Option<Arc<Sender<(u32, Result<SearchResult>)>>>>,
+        pub error_handler: RefCell<Option<Box<dyn Fn(String)>>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for SearchView {
+        const NAME: &'static str = "SearchView";
+        type Type = super::SearchView;
+        type ParentType = gtk::Popover;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+            klass.set_layout_manager_type::<gtk::BinLayout>();
+            klass.set_css_name("searchview");
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for SearchView {
+        fn constructed(&self, obj: &Self::Type) {
+            self.parent_constructed(obj);
+            obj.init_widgets();
+        }
+    }
+    impl WidgetImpl for SearchView {}
+    impl PopoverImpl for SearchView {}
+}
+
+glib::wrapper! {
+    pub struct SearchView(ObjectSubclass<imp::SearchView>)
+        @extends gtk::Widget, gtk::Popover,
+        @implements gio::ActionGroup, gio::ActionMap;
+}
+
+impl Default for SearchView {
+    fn default() -> Self {
+        glib::Object::new(&[]).expect("Failed to create SearchView")
+    }
+}
+
+impl SearchView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn init_widgets(&self) {
+        let imp = self.imp();
+
+        let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        imp.tx.replace(Some(Arc::new(tx)));
+        rx.attach(
+            None,
+            clone!(@weak self as this => @default-return glib::Continue(false), move |(generation, result)| {
+                if generation == this.imp().generation.get() {
+                    this.show_result(result);
+                }
+                glib::Continue(true)
+            }),
+        );
+
+        imp.search_entry
+            .connect_activate(clone!(@weak self as this => move |entry| {
+                this.imp().keyword.replace(entry.text().to_string());
+                this.search(1);
+            }));
+        imp.order_dropdown
+            .connect_selected_notify(clone!(@weak self as this => move |_| {
+                this.search(1);
+            }));
+        imp.prev_btn
+            .connect_clicked(clone!(@weak self as this => move |_| {
+                let page = this.imp().page.get();
+                if page > 1 {
+                    this.search(page - 1);
+                }
+            }));
+        imp.next_btn
+            .connect_clicked(clone!(@weak self as this => move |_| {
+                let imp = this.imp();
+                if imp.page.get() < imp.num_pages.get() {
+                    this.search(imp.page.get() + 1);
+                }
+            }));
+        self.update_page_buttons();
+    }
+
+    fn search(&self, page: u32) {
+        let imp = self.imp();
+        let keyword = imp.keyword.borrow().trim().to_string();
+        if keyword.is_empty() {
+            return;
+        }
+
+        let order = SearchOrder::from_index(imp.order_dropdown.selected());
+        let tx = imp.tx.borrow().clone().unwrap();
+        let generation = imp.generation.get().wrapping_add(1);
+        imp.generation.set(generation);
+        imp.prev_btn.set_sensitive(false);
+        imp.next_btn.set_sensitive(false);
+        std::thread::spawn(move || {
+            let result = search_video(&keyword, page, order);
+            if let Err(e) = &result {
+                warn!("Search {} failed: {}", keyword, e);
+            }
+            tx.send((generation, result)).unwrap();
+        });
+    }
+
+    /// Called with the message of every failed search
+    pub fn connect_error<F: Fn(String) + 'static>(&self, handler: F) {
+        self.imp().error_handler.replace(Some(Box::new(handler)));
+    }
+
+    fn show_result(&self, result: Result<SearchResult>) {
+        let imp = self.imp();
+        let list = imp.results_list.get();
+        while let Some(child) = list.first_child() {
+            list.remove(&child);
+        }
+
+        match result {
+            Ok(result) => {
+                imp.page.set(result.page());
+                imp.num_pages.set(result.num_pages());
+                for video in result.videos() {
+                    list.append(&Self::create_row(video));
+                }
+                imp.results.replace(result.videos().clone());
+            }
+            Err(e) => {
+                imp.page.set(0);
+                imp.num_pages.set(0);
+                imp.results.replace(Vec::new());
+                if let Some(handler) = &*imp.error_handler.borrow() {
+                    handler(format!("搜索失败: {}", e));
+                }
+            }
+        }
+        self.update_page_buttons();
+    }
+
+    fn create_row(video: &SearchVideo) -> gtk::Widget {
+        let title = gtk::Label::builder()
+            .label(&video.title())
+            .xalign(0.0)
+            .max_width_chars(35)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .css_classes(vec!["song-title".to_string()])
+            .build();
+        let detail = format!(
+            "{} · {} · {} 播放",
+            video.author,
+            video.duration,
+            utils::format_count(video.play)
+        );
+        let detail = gtk::Label::builder()
+            .label(&detail)
+            .xalign(0.0)
+            .max_width_chars(35)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .css_classes(vec!["song-artist".to_string()])
+            .build();
+
+        let row = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(3)
+            .build();
+        row.append(&title);
+        row.append(&detail);
+        row.upcast()
+    }
+
+    fn update_page_buttons(&self) {
+        let imp = self.imp();
+        let page = imp.page.get();
+        let num_pages = imp.num_pages.get();
+        imp.prev_btn.set_sensitive(page > 1);
+        imp.next_btn.set_sensitive(page < num_pages);
+        if num_pages == 0 {
+            imp.page_label.set_label("");
+        } else {
+            imp.page_label
+                .set_label(&format!("{} / {}", page, num_pages));
+        }
+    }
+
+    pub fn results_list(&self) -> gtk::ListBox {
+        self.imp().results_list.get()
+    }
+
+    pub fn bvid_at(&self, index: i32) -> Option<String> {
+        let results = self.imp().results.borrow();
+        results.get(index as usize).map(|v| v.bvid.clone())
+    }
+}
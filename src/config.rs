@@ -25,4 +25,6 @@ lazy_static! {
         }
         file
     };
+    pub(crate) static ref SETTINGS_FILE: PathBuf = CONFIG_FILE.with_file_name("settings.json");
+    pub(crate) static ref DATABASE_FILE: PathBuf = CONFIG_FILE.with_file_name("library.db");
 }
@@ -0,0 +1,612 @@
+use std::path::Path;
+
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    audio::{scan_cache, SongData},
+    bilibili::{
+        data::{parse_config, PlayListData, PlaybackData},
+        Result,
+    },
+    config::{CACHE_DIR, DATABASE_FILE},
+};
+
+/// The tables of schema version 1
+const SCHEMA: &str = "
+    CREATE TABLE songs (
+        bvid TEXT NOT NULL,
+        cid INTEGER NOT NULL,
+        -- SongData as JSON, new fields with a serde default need no migration
+        data TEXT NOT NULL,
+        PRIMARY KEY (bvid, cid)
+    );
+    CREATE TABLE playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE TABLE playlist_songs (
+        playlist_id INTEGER NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+        bvid TEXT NOT NULL,
+        cid INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (playlist_id, bvid, cid),
+        FOREIGN KEY (bvid, cid) REFERENCES songs (bvid, cid)
+    );
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY,
+        bvid TEXT NOT NULL,
+        cid INTEGER NOT NULL,
+        played_at INTEGER NOT NULL
+    );
+    CREATE INDEX history_song ON history (bvid, cid);
+    CREATE TABLE cache (
+        bvid TEXT NOT NULL,
+        cid INTEGER NOT NULL,
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        last_access INTEGER NOT NULL,
+        pinned INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (bvid, cid)
+    );
+    CREATE TABLE state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    PRAGMA user_version = 1;
+";
+
+/// How often a song was listened to, and when last
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayStats {
+    pub plays: u32,
+    /// Unix timestamp in seconds
+    pub last_played: i64,
+}
+
+/// A song in the cache directory, as tracked for the size limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub song: SongData,
+    /// Bytes taken by the cached file
+    pub size: u64,
+    /// Unix timestamp in seconds of the last download or playback
+    pub last_access: i64,
+    /// Kept for offline use, never evicted
+    pub pinned: bool,
+}
+
+/// Songs, playlists, play history and cached files, kept in an SQLite
+/// database next to the config. Songs are stored as SongData rows keyed
+/// by bvid and cid.
+pub struct Library {
+    conn: Connection,
+}
+
+impl Library {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::setup(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::setup(Connection::open_in_memory()?)
+    }
+
+    /// The library in DATABASE_FILE, with config.json imported on the first
+    /// run. Without a usable database file, changes last until the app quits.
+    pub fn open_default() -> Self {
+        let library = Library::open(&DATABASE_FILE).unwrap_or_else(|e| {
+            warn!("Unable to open {}: {}", DATABASE_FILE.display(), e);
+            Library::open_in_memory().expect("Unable to create an in-memory database")
+        });
+        if let Err(e) = library.import_config() {
+            warn!("Failed to import config.json: {}", e);
+        }
+        library
+    }
+
+    fn setup(conn: Connection) -> Result<Self> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(SCHEMA)?;
+            tx.commit()?;
+        }
+        Ok(Library { conn })
+    }
+
+    /// Copy the playlists of config.json over, once. Without any songs in
+    /// there, the ones found in the cache are imported.
+    fn import_config(&self) -> Result<()> {
+        if self.state("config_imported")?.is_some() {
+            return Ok(());
+        }
+        let mut config = parse_config().unwrap_or_else(|e| {
+            warn!("Starting with an empty library: {}", e);
+            PlayListData::default()
+        });
+        // Without a config to go on, the tags of the cached files tell
+        // which songs there were
+        if config
+            .playlists
+            .iter()
+            .all(|playlist| playlist.data.is_empty())
+        {
+            config.playlists[config.active].data = scan_cache(&CACHE_DIR);
+        }
+        self.import(&config)
+    }
+
+    fn import(&self, config: &PlayListData) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for (index, playlist) in config.playlists.iter().enumerate() {
+            let id = self.create_playlist(&playlist.name)?;
+            self.insert_songs(id, &playlist.data)?;
+            if index == config.active {
+                self.set_active_playlist(id)?;
+            }
+        }
+        self.set_playback(&config.playback)?;
+        self.set_state("config_imported", "1")?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn state(&self, key: &str) -> Result<Option<String>> {
+        let value = self
+            .conn
+            .query_row("SELECT value FROM state WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(value)
+    }
+
+    fn set_state(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO state (key, value) VALUES (?1, ?2)",
+            [key, value],
+        )?;
+        Ok(())
+    }
+
+    /// The playlist which was loaded into the queue last
+    pub fn active_playlist(&self) -> Result<Option<i64>> {
+        Ok(self
+            .state("active_playlist")?
+            .and_then(|id| id.parse().ok()))
+    }
+
+    pub fn set_active_playlist(&self, id: i64) -> Result<()> {
+        self.set_state("active_playlist", &id.to_string())
+    }
+
+    pub fn playback(&self) -> Result<PlaybackData> {
+        match self.state("playback")? {
+            Some(playback) => Ok(serde_json::from_str(&playback)?),
+            None => Ok(PlaybackData::default()),
+        }
+    }
+
+    pub fn set_playback(&self, playback: &PlaybackData) -> Result<()> {
+        self.set_state("playback", &serde_json::to_string(playback)?)
+    }
+
+    /// Ids and names of the playlists, in the order they were created
+    pub fn playlists(&self) -> Result<Vec<(i64, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, name FROM playlists ORDER BY position")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn playlist_songs(&self, playlist: i64) -> Result<Vec<SongData>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT songs.data FROM playlist_songs
+             JOIN songs USING (bvid, cid)
+             WHERE playlist_id = ?1
+             ORDER BY playlist_songs.position",
+        )?;
+        let rows = stmt.query_map([playlist], |row| row.get::<_, String>(0))?;
+        let mut songs = Vec::new();
+        for data in rows {
+            songs.push(serde_json::from_str(&data?)?);
+        }
+        Ok(songs)
+    }
+
+    pub fn create_playlist(&self, name: &str) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO playlists (name, position)
+             VALUES (?1, (SELECT IFNULL(MAX(position), -1) + 1 FROM playlists))",
+            [name],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn rename_playlist(&self, playlist: i64, name: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE playlists SET name = ?2 WHERE id = ?1",
+            params![playlist, name],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_playlist(&self, playlist: i64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM playlists WHERE id = ?1", [playlist])?;
+        Ok(())
+    }
+
+    /// Copy the songs of `playlist` into a new playlist called `name`
+    pub fn duplicate_playlist(&self, playlist: i64, name: &str) -> Result<i64> {
+        let tx = self.conn.unchecked_transaction()?;
+        let id = self.create_playlist(name)?;
+        self.conn.execute(
+            "INSERT INTO playlist_songs (playlist_id, bvid, cid, position)
+             SELECT ?1, bvid, cid, position FROM playlist_songs WHERE playlist_id = ?2",
+            [id, playlist],
+        )?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Insert the song or save the changes to it
+    pub fn update_song(&self, song: &SongData) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO songs (bvid, cid, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (bvid, cid) DO UPDATE SET data = excluded.data",
+            params![song.bvid(), song.cid(), serde_json::to_string(song)?],
+        )?;
+        Ok(())
+    }
+
+    fn insert_songs(&self, playlist: i64, songs: &[SongData]) -> Result<usize> {
+        let mut added = 0;
+        for song in songs {
+            self.update_song(song)?;
+            added += self.conn.execute(
+                "INSERT OR IGNORE INTO playlist_songs (playlist_id, bvid, cid, position)
+                 VALUES (?1, ?2, ?3, (SELECT IFNULL(MAX(position), -1) + 1
+                                      FROM playlist_songs WHERE playlist_id = ?1))",
+                params![playlist, song.bvid(), song.cid()],
+            )?;
+        }
+        Ok(added)
+    }
+
+    /// Append the songs which are not in `playlist` yet, returns how many
+    /// were added
+    pub fn add_songs(&self, playlist: i64, songs: &[SongData]) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let added = self.insert_songs(playlist, songs)?;
+        tx.commit()?;
+        Ok(added)
+    }
+
+    pub fn remove_songs(&self, playlist: i64, songs: &[SongData]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for song in songs {
+            self.conn.execute(
+                "DELETE FROM playlist_songs WHERE playlist_id = ?1 AND bvid = ?2 AND cid = ?3",
+                params![playlist, song.bvid(), song.cid()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Add a history entry for listening to `song` at `played_at`, a unix
+    /// timestamp in seconds
+    pub fn record_play(&self, song: &SongData, played_at: i64) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.update_song(song)?;
+        self.conn.execute(
+            "INSERT INTO history (bvid, cid, played_at) VALUES (?1, ?2, ?3)",
+            params![song.bvid(), song.cid(), played_at],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn play_stats(&self, song: &SongData) -> Result<Option<PlayStats>> {
+        let stats = self.conn.query_row(
+            "SELECT COUNT(*), MAX(played_at) FROM history WHERE bvid = ?1 AND cid = ?2",
+            params![song.bvid(), song.cid()],
+            |row| {
+                Ok(PlayStats {
+                    plays: row.get(0)?,
+                    last_played: row.get::<_, Option<i64>>(1)?.unwrap_or_default(),
+                })
+            },
+        )?;
+        Ok(Some(stats).filter(|stats| stats.plays > 0))
+    }
+
+    /// The last `limit` plays, newest first
+    pub fn history(&self, limit: u32) -> Result<Vec<(SongData, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT songs.data, history.played_at FROM history
+             JOIN songs USING (bvid, cid)
+             ORDER BY history.played_at DESC, history.id DESC
+             LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let mut history = Vec::new();
+        for row in rows {
+            let (data, played_at) = row?;
+            history.push((serde_json::from_str(&data)?, played_at));
+        }
+        Ok(history)
+    }
+
+    /// The songs listened to most often
+    pub fn most_played(&self, limit: u32) -> Result<Vec<(SongData, PlayStats)>> {
+        self.played_songs("plays DESC, last_played DESC", limit)
+    }
+
+    /// The songs listened to last, each once
+    pub fn recently_played(&self, limit: u32) -> Result<Vec<(SongData, PlayStats)>> {
+        self.played_songs("last_played DESC", limit)
+    }
+
+    fn played_songs(&self, order: &str, limit: u32) -> Result<Vec<(SongData, PlayStats)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT songs.data, COUNT(*) AS plays, MAX(history.played_at) AS last_played
+             FROM history
+             JOIN songs USING (bvid, cid)
+             GROUP BY bvid, cid
+             ORDER BY {}
+             LIMIT ?1",
+            order
+        ))?;
+        let rows = stmt.query_map([limit], |row| {
+            let stats = PlayStats {
+                plays: row.get(1)?,
+                last_played: row.get(2)?,
+            };
+            Ok((row.get::<_, String>(0)?, stats))
+        })?;
+        let mut songs = Vec::new();
+        for row in rows {
+            let (data, stats) = row?;
+            songs.push((serde_json::from_str(&data)?, stats));
+        }
+        Ok(songs)
+    }
+
+    /// Every song which ever was in a playlist
+    pub fn songs(&self) -> Result<Vec<SongData>> {
+        let mut stmt = self.conn.prepare("SELECT data FROM songs")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut songs = Vec::new();
+        for data in rows {
+            songs.push(serde_json::from_str(&data?)?);
+        }
+        Ok(songs)
+    }
+
+    /// Note that the cached file of `song`, `size` bytes, was used at
+    /// `accessed`, a unix timestamp in seconds
+    pub fn touch_cache(&self, song: &SongData, size: u64, accessed: i64) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.update_song(song)?;
+        self.conn.execute(
+            "INSERT INTO cache (bvid, cid, path, size, last_access) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (bvid, cid) DO UPDATE SET
+                 path = excluded.path, size = excluded.size, last_access = excluded.last_access",
+            params![song.bvid(), song.cid(), song.file_name(), size, accessed],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The cached songs, most recently used first
+    pub fn cache_entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT songs.data, cache.size, cache.last_access, cache.pinned FROM cache
+             JOIN songs USING (bvid, cid)
+             ORDER BY cache.last_access DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+            ))
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (data, size, last_access, pinned) = row?;
+            entries.push(CacheEntry {
+                song: serde_json::from_str(&data)?,
+                size,
+                last_access,
+                pinned,
+            });
+        }
+        Ok(entries)
+    }
+
+    pub fn set_pinned(&self, song: &SongData, pinned: bool) -> Result<()> {
+        self.conn.execute(
+            "UPDATE cache SET pinned = ?3 WHERE bvid = ?1 AND cid = ?2",
+            params![song.bvid(), song.cid(), pinned],
+        )?;
+        Ok(())
+    }
+
+    /// The cached file of `song` has been renamed to its current file name
+    pub fn move_cache(&self, song: &SongData) -> Result<()> {
+        self.conn.execute(
+            "UPDATE cache SET path = ?3 WHERE bvid = ?1 AND cid = ?2",
+            params![song.bvid(), song.cid(), song.file_name()],
+        )?;
+        Ok(())
+    }
+
+    /// The file of `song` is gone from the cache
+    pub fn forget_cache(&self, song: &SongData) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM cache WHERE bvid = ?1 AND cid = ?2",
+            params![song.bvid(), song.cid()],
+        )?;
+        Ok(())
+    }
+
+    /// Make `playlist` hold exactly `songs`, in that order
+    pub fn replace_playlist(&self, playlist: i64, songs: &[SongData]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.conn.execute(
+            "DELETE FROM playlist_songs WHERE playlist_id = ?1",
+            [playlist],
+        )?;
+        self.insert_songs(playlist, songs)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bilibili::data::Playlist;
+
+    fn song(bvid: &str, cid: u32) -> SongData {
+        let json = format!(
+            r#"{{"artist":"up","title":"{}","duration":64,"bvid":"{}","cid":{},"album":null}}"#,
+            bvid, bvid, cid
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_import() {
+        let library = Library::open_in_memory().unwrap();
+        let playback = PlaybackData {
+            position: 42,
+            ..PlaybackData::default()
+        };
+        let playlists = vec![
+            Playlist {
+                name: "默认列表".to_string(),
+                data: vec![song("BV1", 1)],
+            },
+            Playlist {
+                name: "工作".to_string(),
+                data: vec![song("BV2", 1), song("BV1", 1)],
+            },
+        ];
+        library
+            .import(&PlayListData::new(playlists.clone(), 1, playback))
+            .unwrap();
+
+        let ids = library.playlists().unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[1].1, "工作");
+        assert_eq!(library.active_playlist().unwrap(), Some(ids[1].0));
+        assert_eq!(library.playlist_songs(ids[1].0).unwrap(), playlists[1].data);
+        assert_eq!(library.playback().unwrap().position, 42);
+        // Only once
+        library.import_config().unwrap();
+        assert_eq!(library.playlists().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_playlist_songs() {
+        let library = Library::open_in_memory().unwrap();
+        let id = library.create_playlist("默认列表").unwrap();
+        let added = library
+            .add_songs(id, &[song("BV1", 1), song("BV2", 1), song("BV1", 2)])
+            .unwrap();
+        assert_eq!(added, 3);
+        // Already there
+        assert_eq!(library.add_songs(id, &[song("BV2", 1)]).unwrap(), 0);
+        library.remove_songs(id, &[song("BV2", 1)]).unwrap();
+        library.add_songs(id, &[song("BV2", 1)]).unwrap();
+        let bvids: Vec<String> = library
+            .playlist_songs(id)
+            .unwrap()
+            .iter()
+            .map(|song| format!("{}-{}", song.bvid(), song.cid()))
+            .collect();
+        assert_eq!(bvids, ["BV1-1", "BV1-2", "BV2-1"]);
+
+        let copy = library.duplicate_playlist(id, "副本").unwrap();
+        library.delete_playlist(id).unwrap();
+        assert_eq!(library.playlist_songs(id).unwrap(), Vec::new());
+        assert_eq!(library.playlist_songs(copy).unwrap().len(), 3);
+
+        library.replace_playlist(copy, &[song("BV3", 1)]).unwrap();
+        assert_eq!(library.playlist_songs(copy).unwrap(), vec![song("BV3", 1)]);
+    }
+
+    #[test]
+    fn test_history() {
+        let library = Library::open_in_memory().unwrap();
+        assert_eq!(library.play_stats(&song("BV1", 1)).unwrap(), None);
+        library.record_play(&song("BV1", 1), 100).unwrap();
+        library.record_play(&song("BV2", 1), 200).unwrap();
+        library.record_play(&song("BV1", 1), 300).unwrap();
+        library.record_play(&song("BV3", 1), 400).unwrap();
+
+        let stats = library.play_stats(&song("BV1", 1)).unwrap().unwrap();
+        assert_eq!(stats.plays, 2);
+        assert_eq!(stats.last_played, 300);
+
+        let history = library.history(3).unwrap();
+        let played: Vec<(String, i64)> = history
+            .iter()
+            .map(|(song, played_at)| (song.bvid(), *played_at))
+            .collect();
+        assert_eq!(
+            played,
+            [
+                ("BV3".to_string(), 400),
+                ("BV1".to_string(), 300),
+                ("BV2".to_string(), 200)
+            ]
+        );
+
+        let most_played = library.most_played(10).unwrap();
+        assert_eq!(most_played[0].0, song("BV1", 1));
+        assert_eq!(most_played[0].1.plays, 2);
+        assert_eq!(most_played[1].0, song("BV3", 1));
+
+        let recent: Vec<String> = library
+            .recently_played(10)
+            .unwrap()
+            .iter()
+            .map(|(song, _)| song.bvid())
+            .collect();
+        assert_eq!(recent, ["BV3", "BV1", "BV2"]);
+    }
+
+    #[test]
+    fn test_cache_entries() {
+        let library = Library::open_in_memory().unwrap();
+        library.touch_cache(&song("BV1", 1), 1000, 100).unwrap();
+        library.touch_cache(&song("BV2", 1), 2000, 200).unwrap();
+        library.set_pinned(&song("BV1", 1), true).unwrap();
+        // Playing it again keeps the pin
+        library.touch_cache(&song("BV1", 1), 1000, 300).unwrap();
+
+        let entries = library.cache_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].song, song("BV1", 1));
+        assert_eq!(entries[0].last_access, 300);
+        assert!(entries[0].pinned);
+        assert_eq!(entries[1].size, 2000);
+        assert!(!entries[1].pinned);
+
+        library.forget_cache(&song("BV1", 1)).unwrap();
+        assert_eq!(library.cache_entries().unwrap().len(), 1);
+        assert_eq!(library.songs().unwrap().len(), 2);
+    }
+}
@@ -2,9 +2,12 @@ mod application;
 mod audio;
 mod bilibili;
 mod config;
+mod library;
 mod playback_control;
 mod playlist_view;
+mod preferences;
 mod queue_row;
+mod settings;
 mod song_row;
 mod utils;
 mod volume_control;
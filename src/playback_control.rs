@@ -1,6 +1,6 @@
 use gtk::{gio, glib, prelude::*, subclass::prelude::*, CompositeTemplate};
 
-use crate::{utils, volume_control::VolumeControl};
+use crate::{audio::RepeatMode, utils, volume_control::VolumeControl};
 
 mod imp {
     use crate::volume_control::VolumeControl;
@@ -14,12 +14,18 @@ mod imp {
         #[template_child]
         pub playback_box: TemplateChild<Box>,
         #[template_child]
+        pub cover_image: TemplateChild<gtk::Image>,
+        #[template_child]
         pub backward_btn: TemplateChild<Button>,
         #[template_child]
         pub forward_btn: TemplateChild<Button>,
         #[template_child]
         pub pause_btn: TemplateChild<Button>,
         #[template_child]
+        pub repeat_btn: TemplateChild<Button>,
+        #[template_child]
+        pub shuffle_btn: TemplateChild<gtk::ToggleButton>,
+        #[template_child]
         pub seek: TemplateChild<Scale>,
         #[template_child]
         pub elapsed_label: TemplateChild<gtk::Label>,
@@ -76,6 +82,22 @@ impl PlaybackControl {
         self.imp().pause_btn.get()
     }
 
+    pub fn set_repeat_mode(&self, repeat_mode: RepeatMode) {
+        let (icon, tooltip) = match repeat_mode {
+            RepeatMode::Consecutive => ("media-playlist-consecutive-symbolic", "顺序播放"),
+            RepeatMode::RepeatAll => ("media-playlist-repeat-symbolic", "列表循环"),
+            RepeatMode::RepeatOne => ("media-playlist-repeat-song-symbolic", "单曲循环"),
+        };
+        let repeat_btn = &self.imp().repeat_btn;
+        repeat_btn.set_icon_name(icon);
+        repeat_btn.set_tooltip_text(Some(tooltip));
+    }
+
+    /// Show the cached cover at `cover`, a placeholder without one
+    pub fn set_cover(&self, cover: Option<&str>) {
+        utils::set_cover(&self.imp().cover_image, cover);
+    }
+
     pub fn seek(&self) -> gtk::Scale {
         self.imp().seek.get()
     }
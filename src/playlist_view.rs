@@ -12,6 +12,8 @@ mod imp {
         #[template_child]
         pub queue_actionbar: TemplateChild<gtk::ActionBar>,
         #[template_child]
+        pub queue_add_button: TemplateChild<gtk::MenuButton>,
+        #[template_child]
         pub queue_remove_button: TemplateChild<gtk::Button>,
         #[template_child]
         pub queue_selected_label: TemplateChild<gtk::Label>,
@@ -73,6 +75,10 @@ impl PlayListView {
         self.imp().queue_actionbar.get()
     }
 
+    pub fn queue_add_button(&self) -> gtk::MenuButton {
+        self.imp().queue_add_button.get()
+    }
+
     pub fn queue_remove_button(&self) -> gtk::Button {
         self.imp().queue_remove_button.get()
     }
@@ -0,0 +1,167 @@
+use std::rc::Rc;
+
+use adw::{prelude::*, subclass::prelude::*};
+use gtk::{
+    gio,
+    glib::{self, clone},
+    prelude::*,
+    CompositeTemplate,
+};
+
+use crate::{audio::AudioPlayer, library::CacheEntry, settings, utils};
+
+mod imp {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[derive(Default, CompositeTemplate)]
+    #[template(resource = "/org/bilibili/music/preferences.ui")]
+    pub struct PreferencesWindow {
+        #[template_child]
+        pub usage_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub clear_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub limit_spin: TemplateChild<gtk::SpinButton>,
+        #[template_child]
+        pub songs_group: TemplateChild<adw::PreferencesGroup>,
+
+        pub player: RefCell<Option<Rc<AudioPlayer>>>,
+        /// The rows of the cached songs, to take them out of the group again
+        pub rows: RefCell<Vec<adw::ActionRow>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for PreferencesWindow {
+        const NAME: &'static str = "BiliBiliPreferencesWindow";
+        type Type = super::PreferencesWindow;
+        type ParentType = adw::PreferencesWindow;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for PreferencesWindow {}
+    impl WidgetImpl for PreferencesWindow {}
+    impl WindowImpl for PreferencesWindow {}
+    impl AdwWindowImpl for PreferencesWindow {}
+    impl PreferencesWindowImpl for PreferencesWindow {}
+}
+
+glib::wrapper! {
+    pub struct PreferencesWindow(ObjectSubclass<imp::PreferencesWindow>)
+        @extends gtk::Widget, gtk::Window, adw::Window, adw::PreferencesWindow,
+        @implements gio::ActionGroup, gio::ActionMap;
+}
+
+impl PreferencesWindow {
+    pub fn new(parent: &gtk::Window, player: Rc<AudioPlayer>) -> Self {
+        let window: Self = glib::Object::new(&[("transient-for", parent)])
+            .expect("Failed to create PreferencesWindow");
+        window.imp().player.replace(Some(player));
+        window.setup_cache();
+        window
+    }
+
+    fn player(&self) -> Rc<AudioPlayer> {
+        self.imp().player.borrow().clone().unwrap()
+    }
+
+    fn setup_cache(&self) {
+        let imp = self.imp();
+        imp.limit_spin.set_value(settings::get().cache_limit as f64);
+        // Evicting right away would drop songs while a new limit is typed
+        imp.limit_spin.connect_value_changed(|spin| {
+            let limit = spin.value() as u64;
+            settings::update(|s| s.cache_limit = limit);
+        });
+        self.connect_close_request(|win| {
+            win.player().apply_cache_limit();
+            gtk::Inhibit(false)
+        });
+        imp.clear_button
+            .connect_clicked(clone!(@weak self as win => move |_| {
+                win.player().clear_cache();
+                win.update_cache();
+            }));
+        self.update_cache();
+    }
+
+    /// List the cached songs again, along with the space they take
+    fn update_cache(&self) {
+        let imp = self.imp();
+        for row in imp.rows.take() {
+            imp.songs_group.remove(&row);
+        }
+
+        let entries = self.player().cache_entries();
+        let total =
+            entries.iter().map(|entry| entry.size).sum::<u64>() + self.player().covers_size();
+        imp.usage_row.set_subtitle(&format!(
+            "{} 首歌曲，共 {}",
+            entries.len(),
+            glib::format_size(total)
+        ));
+        let rows: Vec<adw::ActionRow> = entries
+            .into_iter()
+            .map(|entry| self.cache_row(entry))
+            .collect();
+        for row in &rows {
+            imp.songs_group.add(row);
+        }
+        imp.rows.replace(rows);
+    }
+
+    /// A row with the size and last use of a cached song, and buttons to
+    /// pin or delete it
+    fn cache_row(&self, entry: CacheEntry) -> adw::ActionRow {
+        let row = adw::ActionRow::new();
+        row.set_title(entry.song.title());
+        row.set_subtitle(&format!(
+            "{} · {}",
+            glib::format_size(entry.size),
+            utils::format_unix_time(entry.last_access, "%Y-%m-%d %H:%M")
+        ));
+
+        // The file may have gone since the list was read
+        let cached = entry.song.cache_path().exists();
+        let pin_button = gtk::ToggleButton::new();
+        pin_button.set_icon_name("view-pin-symbolic");
+        pin_button.set_tooltip_text(Some(if cached { "固定" } else { "还没有缓存" }));
+        pin_button.set_valign(gtk::Align::Center);
+        pin_button.set_active(entry.pinned);
+        pin_button.set_sensitive(cached);
+        pin_button.add_css_class("flat");
+        let song = entry.song.clone();
+        pin_button.connect_toggled(clone!(@weak self as win => move |button| {
+            win.player().set_pinned(&song, button.is_active());
+            win.update_cache();
+        }));
+        row.add_suffix(&pin_button);
+
+        let player = self.player();
+        let playing = player
+            .state()
+            .current_song()
+            .map_or(false, |song| song.song_data() == entry.song);
+        let remove_button = gtk::Button::from_icon_name("user-trash-symbolic");
+        remove_button.set_tooltip_text(Some(if playing { "正在播放" } else { "删除" }));
+        remove_button.set_valign(gtk::Align::Center);
+        remove_button.set_sensitive(!playing);
+        remove_button.add_css_class("flat");
+        let song = entry.song;
+        remove_button.connect_clicked(clone!(@weak self as win => move |_| {
+            win.player().remove_cached(&song);
+            win.update_cache();
+        }));
+        row.add_suffix(&remove_button);
+
+        row
+    }
+}
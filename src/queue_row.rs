@@ -7,11 +7,15 @@ use gtk::{
     CompositeTemplate,
 };
 
+use crate::utils;
+
 mod imp {
     use std::cell::{Cell, RefCell};
 
     use gstreamer::glib::once_cell::sync::Lazy;
-    use gtk::glib::{ParamFlags, ParamSpec, ParamSpecBoolean, ParamSpecObject, ParamSpecString};
+    use gtk::glib::{
+        ParamFlags, ParamSpec, ParamSpecBoolean, ParamSpecDouble, ParamSpecObject, ParamSpecString,
+    };
 
     use crate::audio::Song;
 
@@ -23,6 +27,8 @@ mod imp {
         #[template_child]
         pub row_stack: TemplateChild<gtk::Stack>,
         #[template_child]
+        pub cover_image: TemplateChild<gtk::Image>,
+        #[template_child]
         pub song_title_label: TemplateChild<gtk::Label>,
         #[template_child]
         pub song_artist_label: TemplateChild<gtk::Label>,
@@ -32,10 +38,15 @@ mod imp {
         pub selection_title_label: TemplateChild<gtk::Label>,
         #[template_child]
         pub selected_button: TemplateChild<gtk::CheckButton>,
+        #[template_child]
+        pub download_progress_bar: TemplateChild<gtk::ProgressBar>,
 
         pub song: RefCell<Option<Song>>,
+        pub cover: RefCell<Option<String>>,
         pub playing: Cell<bool>,
         pub selection_mode: Cell<bool>,
+        pub cached: Cell<bool>,
+        pub offline: Cell<bool>,
     }
 
     #[glib::object_subclass]
@@ -80,22 +91,40 @@ mod imp {
                     ),
                     ParamSpecString::new("song-artist", "", "", None, ParamFlags::READWRITE),
                     ParamSpecString::new("song-title", "", "", None, ParamFlags::READWRITE),
+                    ParamSpecString::new("cover", "", "", None, ParamFlags::READWRITE),
                     ParamSpecBoolean::new("playing", "", "", false, ParamFlags::READWRITE),
                     ParamSpecBoolean::new("selection-mode", "", "", false, ParamFlags::READWRITE),
                     ParamSpecBoolean::new("selected", "", "", false, ParamFlags::READWRITE),
+                    ParamSpecBoolean::new("unavailable", "", "", false, ParamFlags::READWRITE),
+                    ParamSpecBoolean::new("cached", "", "", false, ParamFlags::READWRITE),
+                    ParamSpecBoolean::new("offline", "", "", false, ParamFlags::READWRITE),
+                    ParamSpecDouble::new(
+                        "download-progress",
+                        "",
+                        "",
+                        0.0,
+                        1.0,
+                        0.0,
+                        ParamFlags::READWRITE,
+                    ),
                 ]
             });
             PROPERTIES.as_ref()
         }
 
-        fn property(&self, _obj: &Self::Type, _id: usize, pspec: &ParamSpec) -> glib::Value {
+        fn property(&self, obj: &Self::Type, _id: usize, pspec: &ParamSpec) -> glib::Value {
             match pspec.name() {
                 "song" => self.song.borrow().to_value(),
                 "song-title" => self.song_title_label.label().to_value(),
                 "song-artist" => self.song_artist_label.label().to_value(),
+                "cover" => self.cover.borrow().to_value(),
                 "playing" => self.playing.get().to_value(),
                 "selection-mode" => self.selection_mode.get().to_value(),
                 "selected" => self.selected_button.is_active().to_value(),
+                "unavailable" => obj.has_css_class("unavailable").to_value(),
+                "cached" => self.cached.get().to_value(),
+                "offline" => self.offline.get().to_value(),
+                "download-progress" => self.download_progress_bar.fraction().to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -124,6 +153,12 @@ mod imp {
                         .expect("song-artist needs to be a string");
                     obj.set_song_artist(p);
                 }
+                "cover" => {
+                    let p = value
+                        .get::<Option<String>>()
+                        .expect("cover needs to be a string");
+                    obj.set_cover(p);
+                }
                 "playing" => {
                     let p = value.get::<bool>().expect("playing needs to be a boolean");
                     obj.set_playing(p);
@@ -138,6 +173,28 @@ mod imp {
                     let p = value.get::<bool>().expect("selected needs to be a boolean");
                     self.selected_button.set_active(p);
                 }
+                "unavailable" => {
+                    let p = value
+                        .get::<bool>()
+                        .expect("unavailable needs to be a boolean");
+                    obj.set_unavailable(p);
+                }
+                "cached" => {
+                    let p = value.get::<bool>().expect("cached needs to be a boolean");
+                    self.cached.set(p);
+                    obj.update_uncached();
+                }
+                "offline" => {
+                    let p = value.get::<bool>().expect("offline needs to be a boolean");
+                    self.offline.set(p);
+                    obj.update_uncached();
+                }
+                "download-progress" => {
+                    let p = value
+                        .get::<f64>()
+                        .expect("download-progress needs to be a double");
+                    obj.set_download_progress(p);
+                }
                 _ => unimplemented!(),
             }
         }
@@ -182,6 +239,11 @@ impl QueueRow {
         imp.selection_artist_label.set_label(artist);
     }
 
+    fn set_cover(&self, cover: Option<String>) {
+        utils::set_cover(&self.imp().cover_image, cover.as_deref());
+        self.imp().cover.replace(cover);
+    }
+
     fn set_playing(&self, playing: bool) {
         if playing != self.imp().playing.replace(playing) {
             self.update_mode();
@@ -196,6 +258,31 @@ impl QueueRow {
         }
     }
 
+    fn set_unavailable(&self, unavailable: bool) {
+        if unavailable {
+            self.add_css_class("unavailable");
+        } else {
+            self.remove_css_class("unavailable");
+        }
+    }
+
+    /// Songs which are not cached can not be played offline
+    fn update_uncached(&self) {
+        let imp = self.imp();
+        if imp.offline.get() && !imp.cached.get() {
+            self.add_css_class("uncached");
+        } else {
+            self.remove_css_class("uncached");
+        }
+    }
+
+    /// The bar is only shown while the download is running
+    fn set_download_progress(&self, progress: f64) {
+        let bar = &self.imp().download_progress_bar;
+        bar.set_fraction(progress);
+        bar.set_visible(progress > 0.0 && progress < 1.0);
+    }
+
     fn update_mode(&self) {
         let imp = self.imp();
         if imp.selection_mode.get() {
@@ -0,0 +1,111 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::{ExportFormat, RepeatMode, ReplayGainMode, DEFAULT_EXPORT_TEMPLATE},
+    bilibili::data::QualityPreference,
+    config::SETTINGS_FILE,
+};
+
+/// User preferences, kept in settings.json next to the playlist
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// Start playing from the network while the song is being cached
+    pub streaming: bool,
+    pub quality: QualityPreference,
+    /// How many of the upcoming songs to download ahead of time
+    pub prefetch: u32,
+    /// Seconds the end of a song overlaps with the next one, 0 to disable
+    pub crossfade: u32,
+    pub replay_gain: ReplayGainMode,
+    /// dB added on top of the replay gain
+    pub pre_amp: f64,
+    pub repeat_mode: RepeatMode,
+    pub shuffle: bool,
+    /// Where songs are exported to, None for the music directory of the user
+    pub export_dir: Option<PathBuf>,
+    /// File name of exported songs, see `export::render_template`
+    pub export_template: String,
+    pub export_format: ExportFormat,
+    /// MiB the cached songs may take, 0 for no limit. Off until the user
+    /// sets one, nothing is evicted behind their back.
+    pub cache_limit: u64,
+    /// Play only from the cache, even when the network is there
+    pub offline: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            streaming: true,
+            quality: QualityPreference::default(),
+            prefetch: 1,
+            crossfade: 0,
+            replay_gain: ReplayGainMode::default(),
+            pre_amp: 0.0,
+            repeat_mode: RepeatMode::default(),
+            shuffle: false,
+            export_dir: None,
+            export_template: DEFAULT_EXPORT_TEMPLATE.to_string(),
+            export_format: ExportFormat::default(),
+            cache_limit: 0,
+            offline: false,
+        }
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Settings> = RwLock::new(load());
+}
+
+fn load() -> Settings {
+    let settings = File::open(&*SETTINGS_FILE)
+        .map_err(|e| e.to_string())
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string()));
+    match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            warn!("Using default settings: {}", e);
+            Settings::default()
+        }
+    }
+}
+
+/// Written to a temporary file first so that a crash never leaves half of it
+fn save(settings: &Settings) -> std::io::Result<()> {
+    let temp = SETTINGS_FILE.with_extension("json.tmp");
+    let file = File::create(&temp)?;
+    let mut buf_writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut buf_writer, settings)?;
+    buf_writer.flush()?;
+    fs::rename(&temp, &*SETTINGS_FILE)
+}
+
+/// A snapshot of the current settings, usable from any thread
+pub fn get() -> Settings {
+    SETTINGS.read().unwrap().clone()
+}
+
+/// Read some of the settings without copying all of them, for the code
+/// that runs on every position update
+pub fn read<T, F: FnOnce(&Settings) -> T>(f: F) -> T {
+    f(&SETTINGS.read().unwrap())
+}
+
+/// Change the settings and write them to disk
+pub fn update<F: FnOnce(&mut Settings)>(f: F) {
+    let mut settings = SETTINGS.write().unwrap();
+    f(&mut settings);
+    if let Err(e) = save(&settings) {
+        warn!("Failed to save settings: {}", e);
+    }
+}
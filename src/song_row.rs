@@ -22,6 +22,8 @@ mod imp {
         #[template_child]
         pub song_title_label: TemplateChild<gtk::Label>,
         #[template_child]
+        pub detail_label: TemplateChild<gtk::Label>,
+        #[template_child]
         pub selected_button: TemplateChild<gtk::CheckButton>,
         pub song: RefCell<Option<Song>>,
     }
@@ -131,4 +133,11 @@ impl SongRow {
         let imp = self.imp();
         imp.song_title_label.set_label(title);
     }
+
+    /// Extra information shown after the title, e.g. when it was played
+    pub fn set_detail(&self, detail: Option<&str>) {
+        let label = &self.imp().detail_label;
+        label.set_label(detail.unwrap_or_default());
+        label.set_visible(detail.is_some());
+    }
 }
@@ -1,3 +1,30 @@
 pub fn format_time(t: u64) -> String {
     format!("{}:{:02}", (t - (t % 60)) / 60, t % 60)
 }
+
+pub fn format_count(n: u64) -> String {
+    if n >= 100_000_000 {
+        format!("{:.1}亿", n as f64 / 100_000_000.0)
+    } else if n >= 10_000 {
+        format!("{:.1}万", n as f64 / 10_000.0)
+    } else {
+        n.to_string()
+    }
+}
+
+/// A unix timestamp in seconds as local time, in the `format` of
+/// `DateTime::format`
+pub fn format_unix_time(t: i64, format: &str) -> String {
+    gtk::glib::DateTime::from_unix_local(t)
+        .and_then(|time| time.format(format))
+        .map(|time| time.to_string())
+        .unwrap_or_default()
+}
+
+/// Show the cover at `path` in `image`, or a placeholder icon
+pub fn set_cover(image: &gtk::Image, path: Option<&str>) {
+    match path {
+        Some(path) => image.set_from_file(Some(path)),
+        None => image.set_icon_name(Some("audio-x-generic-symbolic")),
+    }
+}
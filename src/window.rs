@@ -1,31 +1,69 @@
-use std::sync::Arc;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use adw::subclass::prelude::*;
 use glib::clone;
 use gtk::{
     gdk, gio,
-    glib::{self, MainContext},
+    glib::{self, MainContext, Sender},
     prelude::*,
     subclass::prelude::*,
     CompositeTemplate, SingleSelection,
 };
 
-use crate::audio::{PlayerAction, Song, SongData};
+use log::warn;
+
+use crate::audio::{
+    export_songs, ExportFormat, ExportOptions, ExportProgress, PlayerAction, PlayerError, Queue,
+    ReplayGainMode, Song, SongData, MAX_PRE_AMP,
+};
 use crate::{
-    bilibili::{remove_cache, SongListView},
+    bilibili::{self, data::QualityPreference, parse_input, BiliInput, SongListView},
+    library::{Library, PlayStats},
+    preferences::PreferencesWindow,
     queue_row::QueueRow,
+    settings, utils,
 };
 
+/// Songs to pick from in the SongListView, and the entries that failed
+type SongList = (Vec<SongData>, Vec<String>);
+
+/// Entries of the play history and the smart lists
+const PLAYED_LIMIT: u32 = 100;
+
+/// When a song was played, as shown in the play history
+fn format_played_at(played_at: i64) -> String {
+    utils::format_unix_time(played_at, "%m-%d %H:%M")
+}
+
+/// Where songs are exported to, the music directory unless one was chosen
+fn export_dir() -> PathBuf {
+    settings::get()
+        .export_dir
+        .unwrap_or_else(|| glib::user_special_dir(glib::UserDirectory::Music))
+}
+
 mod imp {
     use glib::{ParamFlags, ParamSpec, ParamSpecBoolean};
     use gstreamer::glib::once_cell::sync::Lazy;
     use gtk::glib;
 
     use crate::{
-        audio::AudioPlayer, bilibili::BvidInputView, playback_control::PlaybackControl,
+        audio::AudioPlayer,
+        bilibili::{BvidInputView, SearchView},
+        playback_control::PlaybackControl,
         playlist_view::PlayListView,
     };
-    use std::{cell::{Cell, RefCell}, rc::Rc};
+    use std::{
+        cell::{Cell, RefCell},
+        collections::HashMap,
+        rc::Rc,
+    };
 
     use super::*;
 
@@ -35,14 +73,26 @@ mod imp {
         #[template_child]
         pub bvid_input_view: TemplateChild<BvidInputView>,
         #[template_child]
+        pub search_view: TemplateChild<SearchView>,
+        #[template_child]
         pub playlist_view: TemplateChild<PlayListView>,
         #[template_child]
         pub playback_ctl: TemplateChild<PlaybackControl>,
+        #[template_child]
+        pub toast_overlay: TemplateChild<adw::ToastOverlay>,
+        #[template_child]
+        pub playlist_dropdown: TemplateChild<gtk::DropDown>,
 
         pub player: Rc<AudioPlayer>,
         pub provider: gtk::CssProvider,
         pub context: MainContext,
         pub playlist_selection: Cell<bool>,
+        pub tx_songs: RefCell<Option<Arc<Sender<SongList>>>>,
+        // Errors whose toast is still around, keyed by the win.retry target
+        pub errors: RefCell<HashMap<u32, PlayerError>>,
+        pub next_error_id: Cell<u32>,
+        // The playlist dropdown is being updated, not switched by the user
+        pub syncing_playlists: Cell<bool>,
     }
 
     #[glib::object_subclass]
@@ -63,6 +113,12 @@ mod imp {
             klass.install_action("win.next", None, move |win, _, _| {
                 win.imp().player.skip_next();
             });
+            klass.install_action("win.repeat", None, move |win, _, _| {
+                let queue = win.imp().player.queue();
+                let repeat_mode = queue.repeat_mode().next();
+                queue.set_repeat_mode(repeat_mode);
+                settings::update(|s| s.repeat_mode = repeat_mode);
+            });
             klass.install_action("win.scroll_to_end", None, move |win, _, _| {
                 let adjustment = win.imp().playlist_view.scroll_adjust();
                 adjustment.set_value(adjustment.upper());
@@ -91,6 +147,71 @@ mod imp {
                 let page = adjustment.page_size();
                 adjustment.set_value(adjustment.value() + page / 2.0);
             });
+            klass.install_action("win.new-playlist", None, move |win, _, _| {
+                win.ask_text("新建播放列表", "新建播放列表", |win, name| {
+                    if let Some(index) = win.edit_playlists(|queue| queue.create_playlist(&name)) {
+                        win.imp().player.switch_playlist(index);
+                    }
+                });
+            });
+            klass.install_action("win.rename-playlist", None, move |win, _, _| {
+                let queue = win.imp().player.queue();
+                let index = queue.active_playlist();
+                let name = queue.playlist_name(index).unwrap_or_default();
+                win.ask_text("重命名播放列表", &name, move |win, name| {
+                    win.edit_playlists(|queue| queue.rename_playlist(index, &name));
+                });
+            });
+            klass.install_action("win.duplicate-playlist", None, move |win, _, _| {
+                let index = win.imp().player.queue().active_playlist();
+                win.edit_playlists(|queue| queue.duplicate_playlist(index));
+            });
+            klass.install_action("win.delete-playlist", None, move |win, _, _| {
+                win.confirm_delete_playlist();
+            });
+            klass.install_action("win.history", None, move |win, _, _| {
+                win.show_history();
+            });
+            klass.install_action("win.most-played", None, move |win, _, _| {
+                win.show_played(
+                    "最常播放",
+                    |library| library.most_played(PLAYED_LIMIT),
+                    |stats| format!("{} 次", stats.plays),
+                );
+            });
+            klass.install_action("win.recently-played", None, move |win, _, _| {
+                win.show_played(
+                    "最近播放",
+                    |library| library.recently_played(PLAYED_LIMIT),
+                    |stats| format_played_at(stats.last_played),
+                );
+            });
+            klass.install_action("win.preferences", None, move |win, _, _| {
+                let player = win.imp().player.clone();
+                PreferencesWindow::new(win.upcast_ref(), player).present();
+            });
+            klass.install_action("win.export", None, move |win, _, _| {
+                win.export_selected();
+            });
+            klass.install_action("win.export-dir", None, move |win, _, _| {
+                win.choose_export_dir();
+            });
+            klass.install_action("win.export-template", None, move |win, _, _| {
+                let template = settings::get().export_template;
+                win.ask_text("文件名模板", &template, |_, template| {
+                    settings::update(|s| s.export_template = template);
+                });
+            });
+            klass.install_action("win.add-to-playlist", Some("u"), move |win, _, param| {
+                if let Some(index) = param.and_then(|p| p.get::<u32>()) {
+                    win.add_selected_to_playlist(index);
+                }
+            });
+            klass.install_action("win.retry", Some("u"), move |win, _, param| {
+                if let Some(id) = param.and_then(|p| p.get::<u32>()) {
+                    win.retry(id);
+                }
+            });
             // 通过 queue-row.ui 的两个 GtkStackPage set_visible_child_name，实现多选控件按需显示的功能
             klass.install_property_action("queue.select", "playlist-selection");
         }
@@ -108,6 +229,13 @@ mod imp {
                 context: MainContext::default(),
                 playlist_selection: Cell::new(false),
                 bvid_input_view: TemplateChild::default(),
+                search_view: TemplateChild::default(),
+                toast_overlay: TemplateChild::default(),
+                tx_songs: RefCell::new(None),
+                errors: RefCell::new(HashMap::new()),
+                next_error_id: Cell::new(0),
+                playlist_dropdown: TemplateChild::default(),
+                syncing_playlists: Cell::new(false),
             }
         }
     }
@@ -115,7 +243,9 @@ mod imp {
     impl ObjectImpl for Window {
         fn constructed(&self, obj: &Self::Type) {
             self.parent_constructed(obj);
+            obj.setup_actions();
             obj.setup_playlist();
+            obj.setup_playlists();
             obj.bind_state();
             obj.connect_signals();
             obj.setup_provider();
@@ -173,7 +303,142 @@ impl Window {
         glib::Object::new(&[("application", application)]).expect("Failed to create Window")
     }
 
+    /// Actions that hold a setting as their state
+    fn setup_actions(&self) {
+        let shuffle = gio::SimpleAction::new_stateful(
+            "shuffle",
+            None,
+            &self.imp().player.queue().shuffled().to_variant(),
+        );
+        shuffle.connect_activate(clone!(@weak self as win => move |action, _| {
+            let queue = win.imp().player.queue();
+            let shuffled = !queue.shuffled();
+            queue.set_shuffled(shuffled);
+            action.set_state(&shuffled.to_variant());
+            settings::update(|s| s.shuffle = shuffled);
+        }));
+        self.add_action(&shuffle);
+
+        let streaming = gio::SimpleAction::new_stateful(
+            "streaming",
+            None,
+            &settings::get().streaming.to_variant(),
+        );
+        streaming.connect_activate(|action, _| {
+            let enabled = !action
+                .state()
+                .and_then(|s| s.get::<bool>())
+                .unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            settings::update(|s| s.streaming = enabled);
+        });
+        self.add_action(&streaming);
+
+        let offline =
+            gio::SimpleAction::new_stateful("offline", None, &settings::get().offline.to_variant());
+        offline.connect_activate(clone!(@weak self as win => move |action, _| {
+            let enabled = !action
+                .state()
+                .and_then(|s| s.get::<bool>())
+                .unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            win.imp().player.set_offline(enabled);
+        }));
+        self.add_action(&offline);
+
+        let quality = gio::SimpleAction::new_stateful(
+            "quality",
+            Some(glib::VariantTy::STRING),
+            &settings::get().quality.to_action_target().to_variant(),
+        );
+        quality.connect_activate(|action, param| {
+            let target = param.and_then(|p| p.get::<String>());
+            if let Some(preference) = target
+                .as_deref()
+                .and_then(QualityPreference::from_action_target)
+            {
+                action.set_state(&preference.to_action_target().to_variant());
+                settings::update(|s| s.quality = preference);
+            }
+        });
+        self.add_action(&quality);
+
+        let prefetch = gio::SimpleAction::new_stateful(
+            "prefetch",
+            Some(glib::VariantTy::UINT32),
+            &settings::get().prefetch.to_variant(),
+        );
+        prefetch.connect_activate(|action, param| {
+            if let Some(lookahead) = param.and_then(|p| p.get::<u32>()) {
+                action.set_state(&lookahead.to_variant());
+                settings::update(|s| s.prefetch = lookahead);
+            }
+        });
+        self.add_action(&prefetch);
+
+        let crossfade = gio::SimpleAction::new_stateful(
+            "crossfade",
+            Some(glib::VariantTy::UINT32),
+            &settings::get().crossfade.to_variant(),
+        );
+        crossfade.connect_activate(|action, param| {
+            if let Some(seconds) = param.and_then(|p| p.get::<u32>()) {
+                action.set_state(&seconds.to_variant());
+                settings::update(|s| s.crossfade = seconds);
+            }
+        });
+        self.add_action(&crossfade);
+
+        let replay_gain = gio::SimpleAction::new_stateful(
+            "replay-gain",
+            Some(glib::VariantTy::STRING),
+            &settings::get().replay_gain.as_str().to_variant(),
+        );
+        replay_gain.connect_activate(|action, param| {
+            let target = param.and_then(|p| p.get::<String>());
+            if let Some(mode) = target.as_deref().and_then(ReplayGainMode::from_name) {
+                action.set_state(&mode.as_str().to_variant());
+                settings::update(|s| s.replay_gain = mode);
+            }
+        });
+        self.add_action(&replay_gain);
+
+        let pre_amp = gio::SimpleAction::new_stateful(
+            "pre-amp",
+            Some(glib::VariantTy::DOUBLE),
+            &settings::get().pre_amp.to_variant(),
+        );
+        pre_amp.connect_activate(|action, param| {
+            if let Some(db) = param.and_then(|p| p.get::<f64>()) {
+                let db = db.clamp(-MAX_PRE_AMP, MAX_PRE_AMP);
+                action.set_state(&db.to_variant());
+                settings::update(|s| s.pre_amp = db);
+            }
+        });
+        self.add_action(&pre_amp);
+
+        let export_format = gio::SimpleAction::new_stateful(
+            "export-format",
+            Some(glib::VariantTy::STRING),
+            &settings::get().export_format.as_str().to_variant(),
+        );
+        export_format.connect_activate(|action, param| {
+            let target = param.and_then(|p| p.get::<String>());
+            if let Some(format) = target.as_deref().and_then(ExportFormat::from_name) {
+                action.set_state(&format.as_str().to_variant());
+                settings::update(|s| s.export_format = format);
+            }
+        });
+        self.add_action(&export_format);
+    }
+
     fn connect_signals(&self) {
+        self.imp()
+            .player
+            .connect_error(clone!(@weak self as win => move |error| {
+                win.show_error(error);
+            }));
+
         let volume_control = self.imp().playback_ctl.volume_control();
         volume_control.connect_notify_local(
             Some("volume"),
@@ -201,7 +466,8 @@ impl Window {
 
                 let queue = imp.player.queue();
                 queue.remove_songs(&remove_songs);
-                remove_cache(&remove_songs);
+                let data: Vec<SongData> = remove_songs.iter().map(|song| song.song_data()).collect();
+                imp.player.drop_cache(&data);
                 win.update_selected_count();
             }));
     }
@@ -224,6 +490,21 @@ impl Window {
                 }
             }),
         );
+        let queue = imp.player.queue();
+        imp.playback_ctl.set_repeat_mode(queue.repeat_mode());
+        queue.connect_notify_local(
+            Some("repeat-mode"),
+            clone!(@weak self as win => move |queue, _| {
+                win.imp().playback_ctl.set_repeat_mode(queue.repeat_mode());
+            }),
+        );
+        imp.playback_ctl.set_cover(state.cover().as_deref());
+        state.connect_notify_local(
+            Some("cover"),
+            clone!(@weak self as win => move |state, _| {
+                win.imp().playback_ctl.set_cover(state.cover().as_deref());
+            }),
+        );
         // Update the position label
         state.connect_notify_local(
             Some("position"),
@@ -237,6 +518,15 @@ impl Window {
                 win.imp().playback_ctl.set_elapsed(elapsed);
             }),
         );
+        // Keep the volume control in sync when the volume is changed
+        // from outside the window, e.g. through MPRIS
+        state.connect_notify_local(
+            Some("volume"),
+            clone!(@weak self as win => move |state, _| {
+                let volume_control = win.imp().playback_ctl.volume_control();
+                volume_control.set_property("volume", state.volume());
+            }),
+        );
         self.imp().playback_ctl.seek().connect_change_value(
             clone!(@strong self as win => move |seek, _, value| {
                 let percent = value / seek.adjustment().upper();
@@ -278,6 +568,10 @@ impl Window {
                 .property_expression("item")
                 .chain_property::<Song>("title")
                 .bind(&row, "song-title", gtk::Widget::NONE);
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("cover")
+                .bind(&row, "cover", gtk::Widget::NONE);
             list_item
                 .property_expression("item")
                 .chain_property::<Song>("playing")
@@ -286,6 +580,24 @@ impl Window {
                 .property_expression("item")
                 .chain_property::<Song>("selected")
                 .bind(&row, "selected", gtk::Widget::NONE);
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("unavailable")
+                .bind(&row, "unavailable", gtk::Widget::NONE);
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("cached")
+                .bind(&row, "cached", gtk::Widget::NONE);
+            win.imp()
+                .player
+                .queue()
+                .bind_property("offline", &row, "offline")
+                .flags(glib::BindingFlags::SYNC_CREATE)
+                .build();
+            list_item
+                .property_expression("item")
+                .chain_property::<Song>("download-progress")
+                .bind(&row, "download-progress", gtk::Widget::NONE);
         }));
         let queue_view = imp.playlist_view.queue_view();
         queue_view.set_factory(Some(&factory));
@@ -307,43 +619,372 @@ impl Window {
         }));
 
         let (tx_songs, rx_songs) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-        let tx_songs = Arc::new(tx_songs);
+        imp.tx_songs.replace(Some(Arc::new(tx_songs)));
 
         self.imp().bvid_input_view.confirm_btn().connect_clicked(
             clone!(@weak self as win => move |_| {
-                let imp = win.imp();
-                let bvid = imp.bvid_input_view.get_input_bvid();
-                let tx = imp.player.tx.clone();
-                let tx_songs = tx_songs.clone();
-                imp.context.spawn(async move {
-                    if let Ok(data) = SongData::from_bvid(bvid.as_str()) {
-                        if data.len() == 1 {
-                            tx.send(PlayerAction::AddSong(data[0].clone())).unwrap();
-                        } else {
-                            tx_songs.send(data).unwrap();
-                        }
-                    }
-                });
+                let view = win.imp().bvid_input_view.get();
+                let (inputs, failed) = parse_input(&view.take_input());
+                if failed.is_empty() {
+                    view.popdown();
+                } else {
+                    view.show_failed(&failed);
+                }
+                win.add_inputs(inputs);
+            }),
+        );
+
+        self.imp()
+            .search_view
+            .connect_error(clone!(@weak self as win => move |message| {
+                win.imp().toast_overlay.add_toast(&adw::Toast::new(&message));
+            }));
+        self.imp().search_view.results_list().connect_row_activated(
+            clone!(@weak self as win => move |_, row| {
+                if let Some(bvid) = win.imp().search_view.bvid_at(row.index()) {
+                    win.add_inputs(vec![BiliInput::Video(bvid, None)]);
+                }
             }),
         );
 
         rx_songs.attach(
             None,
-            clone!(@strong self as win => move |data| {
-                win.create_songlist(data);
+            clone!(@strong self as win => move |(data, invalid)| {
+                win.create_songlist(data, invalid);
                 glib::Continue(true)
             }),
         );
     }
 
-    fn create_songlist(&self, data: Vec<SongData>) {
+    // A single song goes straight into the queue, multiple pages, episodes
+    // or a favorites folder are offered in the SongListView first
+    fn add_inputs(&self, inputs: Vec<BiliInput>) {
+        if inputs.is_empty() {
+            return;
+        }
+
+        let imp = self.imp();
+        let tx = imp.player.tx.clone();
+        let tx_songs = imp.tx_songs.borrow().clone().unwrap();
+        std::thread::spawn(move || {
+            for input in inputs {
+                match SongData::from_input(input.clone()) {
+                    Ok((data, invalid)) if data.len() == 1 && invalid.is_empty() => {
+                        tx.send(PlayerAction::AddSong(data[0].clone())).unwrap();
+                    }
+                    Ok(list) => tx_songs.send(list).unwrap(),
+                    Err(e) => {
+                        warn!("Failed to add songs: {}", e);
+                        let error = PlayerError::AddSongs(input, e);
+                        tx.send(PlayerAction::Error(error)).unwrap();
+                    }
+                }
+            }
+        });
+    }
+
+    /// The playlist switcher, and the menu to add the selected songs of the
+    /// queue to a playlist
+    fn setup_playlists(&self) {
+        let imp = self.imp();
+        let queue = imp.player.queue();
+        let playlists = queue.playlists();
+
+        let dropdown = imp.playlist_dropdown.get();
+        dropdown.set_model(Some(&playlists));
+        dropdown.set_selected(queue.active_playlist());
+        dropdown.connect_notify_local(
+            Some("selected"),
+            clone!(@weak self as win => move |dropdown, _| {
+                let index = dropdown.selected();
+                if !win.imp().syncing_playlists.get() && index != gtk::INVALID_LIST_POSITION {
+                    win.imp().player.switch_playlist(index);
+                }
+            }),
+        );
+        queue.connect_notify_local(
+            Some("active-playlist"),
+            clone!(@weak self as win => move |queue, _| {
+                let dropdown = win.imp().playlist_dropdown.get();
+                if dropdown.selected() != queue.active_playlist() {
+                    dropdown.set_selected(queue.active_playlist());
+                }
+            }),
+        );
+
+        self.update_playlist_menu(&playlists);
+        playlists.connect_items_changed(clone!(@weak self as win => move |playlists, _, _, _| {
+            win.update_playlist_menu(playlists);
+        }));
+    }
+
+    fn update_playlist_menu(&self, playlists: &gio::ListModel) {
+        let menu = gio::Menu::new();
+        let queue = self.imp().player.queue();
+        for index in 0..playlists.n_items() {
+            let name = queue.playlist_name(index).unwrap_or_default();
+            let item = gio::MenuItem::new(Some(&name), None);
+            item.set_action_and_target_value(
+                Some("win.add-to-playlist"),
+                Some(&index.to_variant()),
+            );
+            menu.append_item(&item);
+        }
+        self.imp()
+            .playlist_view
+            .queue_add_button()
+            .set_menu_model(Some(&menu));
+        self.action_set_enabled("win.delete-playlist", playlists.n_items() > 1);
+    }
+
+    /// Change the playlists without the dropdown switching to whatever
+    /// it selects meanwhile
+    fn edit_playlists<T, F: FnOnce(&Queue) -> T>(&self, f: F) -> T {
+        let imp = self.imp();
+        let queue = imp.player.queue();
+        imp.syncing_playlists.set(true);
+        let result = f(queue);
+        imp.playlist_dropdown.set_selected(queue.active_playlist());
+        imp.syncing_playlists.set(false);
+        result
+    }
+
+    fn ask_text<F: Fn(&Self, String) + 'static>(&self, title: &str, text: &str, f: F) {
+        let dialog = gtk::Dialog::with_buttons(
+            Some(title),
+            Some(self),
+            gtk::DialogFlags::MODAL | gtk::DialogFlags::USE_HEADER_BAR,
+            &[
+                ("取消", gtk::ResponseType::Cancel),
+                ("确定", gtk::ResponseType::Accept),
+            ],
+        );
+        dialog.set_default_response(gtk::ResponseType::Accept);
+
+        let entry = gtk::Entry::new();
+        entry.set_text(text);
+        entry.set_activates_default(true);
+        entry.set_margin_top(12);
+        entry.set_margin_bottom(12);
+        entry.set_margin_start(12);
+        entry.set_margin_end(12);
+        dialog.content_area().append(&entry);
+
+        dialog.connect_response(
+            clone!(@weak self as win, @weak entry => move |dialog, response| {
+                let text = entry.text().trim().to_string();
+                if response == gtk::ResponseType::Accept && !text.is_empty() {
+                    f(&win, text);
+                }
+                dialog.destroy();
+            }),
+        );
+        dialog.present();
+    }
+
+    fn confirm_delete_playlist(&self) {
+        let queue = self.imp().player.queue();
+        let index = queue.active_playlist();
+        let name = queue.playlist_name(index).unwrap_or_default();
+        let dialog = gtk::MessageDialog::new(
+            Some(self),
+            gtk::DialogFlags::MODAL,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::OkCancel,
+            &format!("删除播放列表“{}”？", name),
+        );
+        dialog.connect_response(clone!(@weak self as win => move |dialog, response| {
+            if response == gtk::ResponseType::Ok {
+                win.edit_playlists(|queue| queue.delete_playlist(index));
+            }
+            dialog.destroy();
+        }));
+        dialog.present();
+    }
+
+    fn add_selected_to_playlist(&self, index: u32) {
+        let queue = self.imp().player.queue();
+        let songs: Vec<SongData> = (0..queue.n_songs())
+            .filter_map(|pos| queue.song_at(pos))
+            .filter(|song| song.selected())
+            .map(|song| song.song_data())
+            .collect();
+        let added = queue.add_to_playlist(index, &songs);
+        self.set_playlist_selection(false);
+
+        let name = queue.playlist_name(index).unwrap_or_default();
+        let message = format!("已添加 {} 首歌曲到“{}”", added, name);
+        self.imp()
+            .toast_overlay
+            .add_toast(&adw::Toast::new(&message));
+    }
+
+    fn choose_export_dir(&self) {
+        let dialog = gtk::FileChooserDialog::new(
+            Some("选择导出目录"),
+            Some(self),
+            gtk::FileChooserAction::SelectFolder,
+            &[
+                ("取消", gtk::ResponseType::Cancel),
+                ("选择", gtk::ResponseType::Accept),
+            ],
+        );
+        dialog.set_modal(true);
+        let _ = dialog.set_current_folder(Some(&gio::File::for_path(export_dir())));
+        dialog.connect_response(|dialog, response| {
+            if response == gtk::ResponseType::Accept {
+                if let Some(dir) = dialog.file().and_then(|file| file.path()) {
+                    settings::update(|s| s.export_dir = Some(dir));
+                }
+            }
+            dialog.destroy();
+        });
+        dialog.present();
+    }
+
+    /// Copy or transcode the selected songs into the export directory on
+    /// another thread, with a dialog to follow and cancel it
+    fn export_selected(&self) {
+        let queue = self.imp().player.queue();
+        let songs: Vec<SongData> = (0..queue.n_songs())
+            .filter_map(|pos| queue.song_at(pos))
+            .filter(|song| song.selected())
+            .map(|song| song.song_data())
+            .collect();
+        if songs.is_empty() {
+            return;
+        }
+        self.set_playlist_selection(false);
+
+        let settings = settings::get();
+        let options = ExportOptions {
+            dir: export_dir(),
+            template: settings.export_template,
+            format: settings.export_format,
+        };
+
+        let dialog = gtk::Dialog::with_buttons(
+            Some("导出歌曲"),
+            Some(self),
+            gtk::DialogFlags::MODAL | gtk::DialogFlags::USE_HEADER_BAR,
+            &[("取消", gtk::ResponseType::Cancel)],
+        );
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        content.set_margin_top(12);
+        content.set_margin_bottom(12);
+        content.set_margin_start(12);
+        content.set_margin_end(12);
+        let label = gtk::Label::new(None);
+        label.set_xalign(0.0);
+        label.set_ellipsize(gtk::pango::EllipsizeMode::End);
+        label.set_width_chars(30);
+        content.append(&label);
+        let bar = gtk::ProgressBar::new();
+        bar.set_show_text(true);
+        content.append(&bar);
+        dialog.content_area().append(&content);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        dialog.connect_response(clone!(@strong cancel => move |dialog, _| {
+            cancel.store(true, Ordering::Relaxed);
+            dialog.destroy();
+        }));
+        dialog.present();
+
+        let total = songs.len();
+        let dir = options.dir.clone();
+        let (tx, rx) = MainContext::channel(glib::PRIORITY_DEFAULT);
+        rx.attach(
+            None,
+            clone!(@weak self as win, @strong cancel => @default-return glib::Continue(false), move |progress| {
+                match progress {
+                    ExportProgress::Started(index, title) => {
+                        label.set_label(&title);
+                        bar.set_fraction(index as f64 / total as f64);
+                        bar.set_text(Some(&format!("{} / {}", index + 1, total)));
+                        glib::Continue(true)
+                    }
+                    ExportProgress::Finished(failed) => {
+                        dialog.destroy();
+                        let message = if cancel.load(Ordering::Relaxed) {
+                            "已取消导出".to_string()
+                        } else if failed.is_empty() {
+                            format!("已导出 {} 首歌曲到 {}", total, dir.display())
+                        } else {
+                            format!("{} 首歌曲导出失败", failed.len())
+                        };
+                        win.imp().toast_overlay.add_toast(&adw::Toast::new(&message));
+                        glib::Continue(false)
+                    }
+                }
+            }),
+        );
+        std::thread::spawn(move || {
+            export_songs(&songs, &options, cancel, |progress| {
+                let _ = tx.send(progress);
+            });
+        });
+    }
+
+    fn show_error(&self, error: PlayerError) {
+        let imp = self.imp();
+        let toast = adw::Toast::new(&error.message());
+        if !error.can_retry() {
+            imp.toast_overlay.add_toast(&toast);
+            return;
+        }
+
+        let id = imp.next_error_id.get();
+        imp.next_error_id.set(id.wrapping_add(1));
+        toast.set_button_label(Some("重试"));
+        toast.set_action_name(Some("win.retry"));
+        toast.set_action_target_value(Some(&id.to_variant()));
+        // The retry action may still be on its way when the toast goes away
+        toast.connect_dismissed(clone!(@weak self as win => move |_| {
+            glib::idle_add_local_once(clone!(@weak win => move || {
+                win.imp().errors.borrow_mut().remove(&id);
+            }));
+        }));
+
+        imp.errors.borrow_mut().insert(id, error);
+        imp.toast_overlay.add_toast(&toast);
+    }
+
+    fn retry(&self, id: u32) {
+        let imp = self.imp();
+        let error = imp.errors.borrow_mut().remove(&id);
+        match error {
+            Some(PlayerError::Download(data, _) | PlayerError::Playback(data, _)) => {
+                imp.player.retry_song(&data)
+            }
+            Some(PlayerError::AddSongs(input, _)) => self.add_inputs(vec![input]),
+            Some(PlayerError::SyncConfig(_)) => imp.player.queue().sync_config(),
+            None => {}
+        }
+    }
+
+    fn create_songlist(&self, data: Vec<SongData>, invalid: Vec<String>) {
+        let view = self.songlist_view(data);
+        view.set_invalid(&invalid);
+        view.upcast::<gtk::Window>().present();
+    }
+
+    /// A SongListView of `data`, whose selected songs are added to the
+    /// chosen playlist
+    fn songlist_view(&self, data: Vec<SongData>) -> SongListView {
         let view = SongListView::new(self.dynamic_cast_ref::<gtk::Window>().unwrap());
         view.init(data);
+        let queue = self.imp().player.queue();
+        view.set_playlists(&queue.playlists(), queue.active_playlist());
 
         view.confirm_btn()
             .connect_clicked(clone!(@weak self as win, @weak view => move |_| {
-                if let Some(data) = view.selected_songs() {
-                    win.imp().player.queue().add_songs(&data);
+                if let Some(songs) = view.selected_songs() {
+                    let data: Vec<SongData> = songs.iter().map(|song| song.song_data()).collect();
+                    win.imp()
+                        .player
+                        .queue()
+                        .add_to_playlist(view.target_playlist(), &data);
                 }
                 view.upcast::<gtk::Window>().destroy();
             }));
@@ -353,8 +994,64 @@ impl Window {
                 view.upcast::<gtk::Window>().destroy();
             }));
 
-        let w = view.upcast::<gtk::Window>();
-        w.present();
+        view
+    }
+
+    /// The last plays, newest first, to send to the queue again
+    fn show_history(&self) {
+        let history = match self.imp().player.library().history(PLAYED_LIMIT) {
+            Ok(history) => history,
+            Err(e) => {
+                warn!("Failed to read the play history: {}", e);
+                let message = format!("无法读取播放历史: {}", e);
+                self.imp()
+                    .toast_overlay
+                    .add_toast(&adw::Toast::new(&message));
+                return;
+            }
+        };
+        let details = history
+            .iter()
+            .map(|(_, played_at)| format_played_at(*played_at))
+            .collect();
+        let data = history.into_iter().map(|(data, _)| data).collect();
+        self.present_played("播放历史", data, details);
+    }
+
+    /// A smart list of the played songs, `detail` describes the stats of
+    /// each
+    fn show_played<F, D>(&self, title: &str, songs: F, detail: D)
+    where
+        F: FnOnce(&Library) -> bilibili::Result<Vec<(SongData, PlayStats)>>,
+        D: Fn(&PlayStats) -> String,
+    {
+        let songs = match songs(self.imp().player.library()) {
+            Ok(songs) => songs,
+            Err(e) => {
+                warn!("Failed to read the play history: {}", e);
+                let message = format!("无法读取播放历史: {}", e);
+                self.imp()
+                    .toast_overlay
+                    .add_toast(&adw::Toast::new(&message));
+                return;
+            }
+        };
+        let details = songs.iter().map(|(_, stats)| detail(stats)).collect();
+        let data = songs.into_iter().map(|(data, _)| data).collect();
+        self.present_played(title, data, details);
+    }
+
+    fn present_played(&self, title: &str, data: Vec<SongData>, details: Vec<String>) {
+        if data.is_empty() {
+            self.imp()
+                .toast_overlay
+                .add_toast(&adw::Toast::new("还没有播放记录"));
+            return;
+        }
+        let view = self.songlist_view(data);
+        view.set_details(details);
+        view.set_title(Some(title));
+        view.upcast::<gtk::Window>().present();
     }
 
     fn setup_provider(&self) {